@@ -0,0 +1,74 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! Multiplexes many yamux connections on a single thread.
+//!
+//! Every connection runs over an in-memory transport. The client side of each
+//! connection opens a number of streams one after another and has the server
+//! side echo a message back.
+//!
+//! Usage: `cargo run --example many_connections [connections] [streams]`
+
+extern crate futures;
+extern crate tokio;
+extern crate yamux;
+
+use futures::{future::{self, Loop}, prelude::*};
+use std::{env, time::Instant};
+use tokio::{io, runtime::current_thread::{self, Runtime}};
+use yamux::{memory, Config, Connection, Mode};
+
+const MSG_LEN: usize = 1024;
+
+fn main() {
+    let mut args = env::args().skip(1).map(|a| a.parse::<usize>().expect("numeric argument"));
+    let connections = args.next().unwrap_or(500);
+    let streams = args.next().unwrap_or(20);
+
+    let mut rt = Runtime::new().unwrap();
+    let start = Instant::now();
+
+    let clients = (0 .. connections).map(|_| {
+        let (a, b) = memory::pair();
+
+        let server = Connection::new(b, Config::default(), Mode::Server)
+            .for_each(|stream| {
+                let echo = io::read_exact(stream, vec![0; MSG_LEN])
+                    .and_then(|(stream, msg)| io::write_all(stream, msg))
+                    .and_then(|(stream, _)| io::flush(stream))
+                    .map(|_| ())
+                    .map_err(|e| eprintln!("server stream error: {}", e));
+                current_thread::spawn(echo);
+                Ok(())
+            })
+            .map_err(|e| eprintln!("server connection error: {}", e));
+        rt.spawn(server);
+
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        future::loop_fn(0, move |i| {
+            let stream = conn.open_stream().unwrap().expect("connection is alive");
+            io::write_all(stream, vec![0x42; MSG_LEN])
+                .and_then(|(stream, _)| io::flush(stream))
+                .and_then(|stream| io::read_exact(stream, vec![0; MSG_LEN]))
+                .map(move |_| if i + 1 == streams { Loop::Break(()) } else { Loop::Continue(i + 1) })
+        })
+    })
+    .collect::<Vec<_>>();
+
+    rt.block_on(future::join_all(clients)).expect("all clients succeed");
+
+    let elapsed = start.elapsed();
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    println!("{} connections x {} streams in {:.3}s ({:.0} streams/s)",
+        connections,
+        streams,
+        secs,
+        (connections * streams) as f64 / secs)
+}
@@ -0,0 +1,253 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! Measures yamux throughput and latency between two instances over TCP.
+//!
+//! Start a server with
+//!
+//!     cargo run --release --example perf server 127.0.0.1:5201
+//!
+//! and run one of the client modes against it:
+//!
+//!     cargo run --release --example perf client 127.0.0.1:5201 single <MiB>
+//!     cargo run --release --example perf client 127.0.0.1:5201 many <streams> <MiB per stream>
+//!     cargo run --release --example perf client 127.0.0.1:5201 open <streams>
+//!
+//! `single` and `many` measure throughput, `open` measures how long it takes
+//! to open a stream and get a first byte back.
+
+extern crate futures;
+extern crate tokio;
+extern crate yamux;
+
+use futures::{future::{self, Either, Loop}, prelude::*};
+use std::{env, io, net::SocketAddr, process, time::{Duration, Instant}};
+use tokio::{io as tio, net::{TcpListener, TcpStream}, runtime::current_thread::{self, Runtime}};
+use yamux::{Config, Connection, Mode, Stats, StreamHandle, WindowUpdateMode};
+
+const CHUNK_LEN: usize = 64 * 1024;
+const MIB: u64 = 1024 * 1024;
+
+// First byte of every stream, telling the server what to do.
+const THROUGHPUT: u8 = b'T';
+const LATENCY: u8 = b'L';
+
+type Handle = StreamHandle<TcpStream>;
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.len() < 2 {
+        usage()
+    }
+    let addr = args[1].parse::<SocketAddr>().unwrap_or_else(|_| usage());
+    let num = |i: usize| args.get(i).and_then(|a| a.parse::<u64>().ok()).unwrap_or_else(|| usage());
+    let mut rt = Runtime::new().unwrap();
+    let result = match (args[0].as_str(), args.get(2).map(String::as_str)) {
+        ("server", None) => rt.block_on(server(addr)),
+        ("client", Some("single")) => rt.block_on(client(addr, Test::Throughput(1, num(3) * MIB))),
+        ("client", Some("many")) => rt.block_on(client(addr, Test::Throughput(num(3), num(4) * MIB))),
+        ("client", Some("open")) => rt.block_on(client(addr, Test::Latency(num(3)))),
+        _ => usage()
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1)
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: perf server <addr>");
+    eprintln!("       perf client <addr> single <MiB>");
+    eprintln!("       perf client <addr> many <streams> <MiB per stream>");
+    eprintln!("       perf client <addr> open <streams>");
+    process::exit(1)
+}
+
+/// Window updates are sent as data is consumed so that fast senders do not
+/// overrun the buffers of the receiving side.
+fn config() -> Config {
+    let mut cfg = Config::default();
+    cfg.set_window_update_mode(WindowUpdateMode::OnRead);
+    cfg
+}
+
+enum Test {
+    /// Number of streams and bytes per stream.
+    Throughput(u64, u64),
+    /// Number of streams to open one after another.
+    Latency(u64)
+}
+
+fn server(addr: SocketAddr) -> impl Future<Item=(), Error=io::Error> {
+    let listener = TcpListener::bind(&addr).into_future();
+    listener.and_then(move |listener| {
+        println!("listening on {}", addr);
+        listener.incoming().for_each(|socket| {
+            let peer = socket.peer_addr()?;
+            socket.set_nodelay(true)?;
+            let conn = Connection::new(socket, config(), Mode::Server);
+            let c = conn.clone();
+            let serve = conn.for_each(|stream| {
+                current_thread::spawn(handle(stream).map_err(|e| eprintln!("stream error: {}", e)));
+                Ok(())
+            })
+            .then(move |result| {
+                if let Err(e) = result {
+                    eprintln!("connection error: {}", e)
+                }
+                println!("connection from {} closed", peer);
+                print_stats(&c.stats());
+                Ok(())
+            });
+            current_thread::spawn(serve);
+            Ok(())
+        })
+    })
+}
+
+fn handle(stream: Handle) -> impl Future<Item=(), Error=io::Error> {
+    tio::read_exact(stream, [0; 1]).and_then(|(stream, kind)| {
+        match kind[0] {
+            LATENCY => Either::A(Either::A(future::ok(stream))),
+            THROUGHPUT => {
+                let sink = tio::read_exact(stream, [0; 8])
+                    .and_then(|(stream, len)| discard(stream, u64::from_be_bytes(len)));
+                Either::A(Either::B(sink))
+            }
+            k => Either::B(future::err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown test {}", k))))
+        }
+        .and_then(move |stream| tio::write_all(stream, kind))
+        .and_then(|(stream, _)| tio::flush(stream))
+        .map(|_| ())
+    })
+}
+
+fn client(addr: SocketAddr, test: Test) -> impl Future<Item=(), Error=io::Error> {
+    TcpStream::connect(&addr)
+        .and_then(|socket| socket.set_nodelay(true).map(|()| socket))
+        .and_then(move |socket| {
+            let conn = Connection::new(socket, config(), Mode::Client);
+            let c = conn.clone();
+            match test {
+                Test::Throughput(n, len) => Either::A(throughput(conn, n, len)),
+                Test::Latency(n) => Either::B(latency(conn, n))
+            }
+            .map(move |()| print_stats(&c.stats()))
+        })
+}
+
+fn throughput(conn: Connection<TcpStream>, n: u64, len: u64) -> impl Future<Item=(), Error=io::Error> {
+    let start = Instant::now();
+    let streams = (0 .. n).map(|_| {
+        open(&conn).into_future().and_then(move |stream| {
+            let mut header = vec![THROUGHPUT];
+            header.extend_from_slice(&len.to_be_bytes());
+            tio::write_all(stream, header)
+                .and_then(move |(stream, _)| send(stream, len))
+                .and_then(tio::flush)
+                .and_then(|stream| tio::read_exact(stream, [0; 1]))
+        })
+    })
+    .collect::<Vec<_>>();
+    future::join_all(streams).map(move |_| {
+        let elapsed = secs(start.elapsed());
+        let total = n * len;
+        println!("{} stream(s), {} MiB total in {:.3}s: {:.2} MiB/s",
+            n,
+            total / MIB,
+            elapsed,
+            total as f64 / MIB as f64 / elapsed)
+    })
+}
+
+fn latency(conn: Connection<TcpStream>, n: u64) -> impl Future<Item=(), Error=io::Error> {
+    future::loop_fn(Vec::with_capacity(n as usize), move |mut samples| {
+        let start = Instant::now();
+        open(&conn).into_future()
+            .and_then(|stream| tio::write_all(stream, [LATENCY]))
+            .and_then(|(stream, _)| tio::flush(stream))
+            .and_then(|stream| tio::read_exact(stream, [0; 1]))
+            .map(move |_| {
+                samples.push(start.elapsed());
+                if samples.len() as u64 == n {
+                    Loop::Break(samples)
+                } else {
+                    Loop::Continue(samples)
+                }
+            })
+    })
+    .map(|mut samples| {
+        samples.sort();
+        let total = samples.iter().fold(Duration::from_secs(0), |a, &d| a + d);
+        println!("opened {} streams: min {:.3}ms, avg {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            samples.len(),
+            millis(samples[0]),
+            millis(total) / samples.len() as f64,
+            millis(samples[samples.len() * 99 / 100]),
+            millis(samples[samples.len() - 1]))
+    })
+}
+
+fn open(conn: &Connection<TcpStream>) -> Result<Handle, io::Error> {
+    match conn.open_stream() {
+        Ok(Some(stream)) => Ok(stream),
+        Ok(None) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+/// Write `len` bytes to the stream.
+fn send(stream: Handle, len: u64) -> impl Future<Item=Handle, Error=io::Error> {
+    future::loop_fn((stream, vec![0x5a; CHUNK_LEN], len), |(stream, mut chunk, remaining)| {
+        if remaining == 0 {
+            return Either::A(future::ok(Loop::Break(stream)))
+        }
+        if remaining < chunk.len() as u64 {
+            chunk.truncate(remaining as usize)
+        }
+        Either::B(tio::write_all(stream, chunk).map(move |(stream, chunk)| {
+            let remaining = remaining - chunk.len() as u64;
+            Loop::Continue((stream, chunk, remaining))
+        }))
+    })
+}
+
+/// Read and discard `len` bytes from the stream.
+fn discard(stream: Handle, len: u64) -> impl Future<Item=Handle, Error=io::Error> {
+    future::loop_fn((stream, vec![0; CHUNK_LEN], len), |(stream, buf, remaining)| {
+        if remaining == 0 {
+            return Either::A(future::ok(Loop::Break(stream)))
+        }
+        Either::B(tio::read(stream, buf).and_then(move |(stream, buf, n)| {
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into())
+            }
+            Ok(Loop::Continue((stream, buf, remaining.saturating_sub(n as u64))))
+        }))
+    })
+}
+
+fn print_stats(stats: &Stats) {
+    println!("frames sent/received: {}/{}, bytes sent/received: {}/{}, streams opened/accepted: {}/{}",
+        stats.frames_sent(),
+        stats.frames_received(),
+        stats.bytes_sent(),
+        stats.bytes_received(),
+        stats.streams_opened(),
+        stats.streams_accepted())
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+fn millis(d: Duration) -> f64 {
+    secs(d) * 1e3
+}
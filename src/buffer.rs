@@ -0,0 +1,34 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use bytes::BytesMut;
+
+/// Supplies the memory of the buffers a connection allocates.
+///
+/// Used for the initial read and write buffers of the frame codec and for
+/// the bodies of outbound data frames, e.g. to account for memory or to hand
+/// out buffers backed by an arena. Buffers which grow beyond the capacity
+/// they have been allocated with are reallocated by `bytes`.
+///
+/// See `Builder::buffer_provider`.
+pub trait BufferProvider: Send + Sync {
+    /// Get an empty buffer with room for at least `capacity` bytes.
+    fn alloc(&self, capacity: usize) -> BytesMut;
+}
+
+/// Allocates buffers with the global allocator. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobalBuffers;
+
+impl BufferProvider for GlobalBuffers {
+    fn alloc(&self, capacity: usize) -> BytesMut {
+        BytesMut::with_capacity(capacity)
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! A monotonic clock for timeouts which survives suspend and resume.
+//!
+//! On most platforms, e.g. Linux and macOS, `Instant`s do not advance while a
+//! machine is suspended, while the wall-clock time does. A `Clock` reads both
+//! and treats a difference of more than `MAX_SKEW` in how far they advanced
+//! between two reads as a jump. Setting the wall-clock is detected as a jump
+//! too. Timestamps taken before a jump are void, i.e. whoever uses them re-arms
+//! their timeout instead of firing it. How often the clock is read does not
+//! matter.
+//!
+//! Where `Instant`s include the time suspended, e.g. on Windows, both advance
+//! alike. A suspend can then not be told apart from a long pause between two
+//! reads, and timeouts which have expired in the meantime fire on resume.
+
+use log::debug;
+use std::{cmp::{max, min}, time::{Duration, Instant, SystemTime}};
+
+/// The max. difference between the advance of `Instant`s and the wall-clock
+/// time which is not considered a jump.
+pub(crate) const MAX_SKEW: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) struct Clock {
+    last: Instant,
+    last_wall: SystemTime,
+    /// Number of jumps seen so far.
+    epoch: u64
+}
+
+/// A point in time read from a `Clock`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timestamp {
+    instant: Instant,
+    epoch: u64
+}
+
+impl Timestamp {
+    /// Time passed since `earlier`, or `None` if the clock has jumped since.
+    pub(crate) fn duration_since(self, earlier: Timestamp) -> Option<Duration> {
+        if self.epoch != earlier.epoch {
+            return None
+        }
+        Some(self.instant.duration_since(earlier.instant))
+    }
+
+    /// A timestamp `d` earlier, if the `Instant` can represent it.
+    #[cfg(test)]
+    pub(crate) fn checked_sub(self, d: Duration) -> Option<Timestamp> {
+        self.instant.checked_sub(d).map(|instant| Timestamp { instant, epoch: self.epoch })
+    }
+}
+
+impl Clock {
+    pub(crate) fn new() -> Self {
+        Clock { last: Instant::now(), last_wall: SystemTime::now(), epoch: 0 }
+    }
+
+    pub(crate) fn now(&mut self) -> Timestamp {
+        self.observe(Instant::now(), SystemTime::now())
+    }
+
+    fn observe(&mut self, now: Instant, wall: SystemTime) -> Timestamp {
+        let step = now.duration_since(self.last);
+        // The wall-clock may also go backwards, e.g. when it is set.
+        let wall_step = wall.duration_since(self.last_wall).unwrap_or_else(|e| e.duration());
+        let skew = max(step, wall_step) - min(step, wall_step);
+        if skew > MAX_SKEW {
+            debug!("clock jumped: {:?} passed, wall-clock advanced by {:?}, re-arming timeouts", step, wall_step);
+            self.epoch += 1
+        }
+        self.last = now;
+        self.last_wall = wall;
+        Timestamp { instant: now, epoch: self.epoch }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jumps_void_earlier_timestamps() {
+        let mut clock = Clock::new();
+        let (start, wall) = (clock.last, clock.last_wall);
+        let t0 = clock.observe(start, wall);
+        let t1 = clock.observe(start + MAX_SKEW, wall + MAX_SKEW);
+        let t2 = clock.observe(start + 2 * MAX_SKEW, wall + 2 * MAX_SKEW);
+        assert_eq!(Some(2 * MAX_SKEW), t2.duration_since(t0));
+
+        // Suspended for an hour: `Instant`s stand still, the wall-clock does not.
+        let hour = Duration::from_secs(3600);
+        let t3 = clock.observe(start + 2 * MAX_SKEW, wall + 2 * MAX_SKEW + hour);
+        assert_eq!(None, t3.duration_since(t1));
+        assert_eq!(None, t3.duration_since(t2));
+        let t4 = clock.observe(start + 3 * MAX_SKEW, wall + 3 * MAX_SKEW + hour);
+        assert_eq!(Some(MAX_SKEW), t4.duration_since(t3));
+
+        // The wall-clock is set back an hour.
+        let t5 = clock.observe(start + 4 * MAX_SKEW, wall + 4 * MAX_SKEW);
+        assert_eq!(None, t5.duration_since(t4))
+    }
+
+    #[test]
+    fn sparse_reads_are_no_jumps() {
+        // E.g. a congestion threshold of 45s with the connection polled
+        // every 31s.
+        let threshold = Duration::from_secs(45);
+        let step = Duration::from_secs(31);
+        let mut clock = Clock::new();
+        let (start, wall) = (clock.last, clock.last_wall);
+        let blocked_since = clock.observe(start, wall);
+        let t1 = clock.observe(start + step, wall + step);
+        assert!(t1.duration_since(blocked_since).unwrap() < threshold);
+        let t2 = clock.observe(start + 2 * step, wall + 2 * step);
+        assert!(t2.duration_since(blocked_since).unwrap() >= threshold)
+    }
+}
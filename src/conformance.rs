@@ -0,0 +1,192 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! Conformance vectors for other yamux implementations.
+//!
+//! Each scenario feeds frames to a fresh `Session` and records the frames it
+//! answers with, which streams it accepts and whether it terminates. Run
+//!
+//! ```text
+//! YAMUX_CONFORMANCE_OUT=vectors.json cargo test generate_conformance_vectors -- --ignored
+//! ```
+//!
+//! to write the corpus as JSON. Frames are encoded like in `Snapshot`s, with
+//! bodies as hex strings.
+
+use bytes::Bytes;
+use crate::{
+    Config,
+    DEFAULT_CREDIT,
+    connection::Mode,
+    frame::{header::{Flags, Header, FIN, RST, SYN, UNLIMITED}, Frame, RawFrame},
+    session::{Action, Session},
+    snapshot::FrameSnapshot,
+    stream
+};
+use std::{env, fmt::Write, fs};
+
+/// Version of the corpus format.
+const VERSION: u32 = 1;
+
+struct Scenario {
+    name: &'static str,
+    mode: Mode,
+    input: Vec<RawFrame>
+}
+
+fn syn(id: u32) -> RawFrame {
+    let mut frame = Frame::window_update(stream::Id::new(id), DEFAULT_CREDIT);
+    frame.header_mut().syn();
+    frame.into_raw()
+}
+
+fn data(id: u32, body: &'static str) -> RawFrame {
+    Frame::data(stream::Id::new(id), Bytes::from(body)).into_raw()
+}
+
+fn with_flags(mut frame: RawFrame, flags: Flags) -> RawFrame {
+    frame.header.flags = frame.header.flags.and(flags);
+    frame
+}
+
+fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "inbound stream opened by window update",
+            mode: Mode::Server,
+            input: vec![syn(1)]
+        },
+        Scenario {
+            name: "inbound stream opened by data",
+            mode: Mode::Server,
+            input: vec![with_flags(data(1, "hello"), SYN)]
+        },
+        Scenario {
+            name: "inbound stream opened by empty data",
+            mode: Mode::Server,
+            input: vec![with_flags(data(1, ""), SYN), Frame::window_update(stream::Id::new(1), DEFAULT_CREDIT).into_raw()]
+        },
+        Scenario {
+            name: "inbound stream with wrong parity",
+            mode: Mode::Server,
+            input: vec![syn(2)]
+        },
+        Scenario {
+            name: "inbound stream opened twice",
+            mode: Mode::Client,
+            input: vec![syn(2), syn(2)]
+        },
+        Scenario {
+            name: "stream half-closed by remote",
+            mode: Mode::Server,
+            input: vec![syn(1), with_flags(data(1, "bye"), FIN)]
+        },
+        Scenario {
+            name: "stream reset by remote",
+            mode: Mode::Server,
+            input: vec![syn(1), with_flags(data(1, ""), RST), data(1, "late")]
+        },
+        Scenario {
+            name: "open with empty data frame",
+            mode: Mode::Server,
+            input: vec![with_flags(data(1, ""), SYN), Frame::window_update(stream::Id::new(1), 1024).into_raw(), data(1, "hello")]
+        },
+        Scenario {
+            name: "open with window update increment",
+            mode: Mode::Server,
+            input: vec![with_flags(Frame::window_update(stream::Id::new(1), 0).into_raw(), SYN), data(1, "hello")]
+        },
+        Scenario {
+            name: "data for unknown stream",
+            mode: Mode::Server,
+            input: vec![data(3, "hello")]
+        },
+        Scenario {
+            name: "credit overflow",
+            mode: Mode::Server,
+            input: vec![syn(1), Frame::window_update(stream::Id::new(1), u32::MAX).into_raw()]
+        },
+        Scenario {
+            name: "unlimited stream declined",
+            mode: Mode::Server,
+            input: vec![with_flags(syn(1), UNLIMITED), data(1, "hello")]
+        },
+        Scenario {
+            name: "max. window",
+            mode: Mode::Server,
+            input: vec![with_flags(Frame::window_update(stream::Id::new(1), u32::MAX).into_raw(), SYN)]
+        },
+        Scenario {
+            name: "ping",
+            mode: Mode::Server,
+            input: vec![with_flags(Frame::new(Header::ping(42)).into_raw(), SYN)]
+        },
+        Scenario {
+            name: "go away",
+            mode: Mode::Client,
+            input: vec![syn(2), Frame::go_away(0).into_raw(), syn(4)]
+        }
+    ]
+}
+
+/// Run the scenarios and render the results as JSON.
+fn generate() -> String {
+    let mut json = format!("{{\"version\":{},\"scenarios\":[", VERSION);
+    for (i, scenario) in scenarios().into_iter().enumerate() {
+        let mut session = Session::new(Config::default(), scenario.mode);
+        let mut output = Vec::new();
+        let mut accepted = Vec::new();
+        let mut terminated = false;
+        for frame in &scenario.input {
+            for action in session.handle_frame(frame.clone()) {
+                match action {
+                    Action::Send(frame, _) => output.push(frame),
+                    Action::Incoming(id) => accepted.push(id.as_u32()),
+                    Action::Terminate(_) => terminated = true,
+                    Action::Pong(_) | Action::UnexpectedPong(_) | Action::GoAway(_) => {}
+                }
+            }
+        }
+        if i > 0 {
+            json.push(',')
+        }
+        write!(json, "{{\"name\":\"{}\",\"mode\":\"{:?}\",\"input\":[{}],\"output\":[{}],\"accepted\":{:?},\"terminated\":{}}}",
+            scenario.name,
+            scenario.mode,
+            frames(&scenario.input),
+            frames(&output),
+            accepted,
+            terminated).expect("writing to a string succeeds")
+    }
+    json.push_str("]}");
+    json
+}
+
+fn frames(frames: &[RawFrame]) -> String {
+    frames.iter().map(|f| FrameSnapshot::new(f, true).to_string()).collect::<Vec<_>>().join(",")
+}
+
+#[test]
+fn conformance_vectors_are_deterministic() {
+    let json = generate();
+    assert_eq!(json, generate());
+    assert!(json.contains("\"name\":\"ping\",\"mode\":\"Server\""));
+    assert!(json.contains("\"output\":[{\"type\":\"Ping\",\"flags\":2,\"stream_id\":0,\"length\":42,\"body\":\"\"}]"))
+}
+
+#[test]
+#[ignore]
+fn generate_conformance_vectors() {
+    let json = generate();
+    match env::var("YAMUX_CONFORMANCE_OUT") {
+        Ok(path) => fs::write(path, json).expect("failed to write conformance vectors"),
+        Err(_) => println!("{}", json)
+    }
+}
@@ -8,45 +8,52 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use crate::{
+    BufferProvider,
     Config,
-    DEFAULT_CREDIT,
-    WindowUpdateMode,
+    ConfigProvider,
+    GlobalBuffers,
+    IdAllocator,
+    clock::Timestamp,
     error::ConnectionError,
-    frame::{
-        codec::FrameCodec,
-        header::{self, ACK, ECODE_INTERNAL, ECODE_PROTO, FIN, Header, RST, SYN, Type},
-        Data,
-        Frame,
-        GoAway,
-        Ping,
-        RawFrame,
-        WindowUpdate
-    },
+    event::{ConnectionEvent, CustomEvent, Subscribers},
+    frame::{codec::FrameCodec, header::{Flags, Type, RST, SYN}, GoAwayReason, RawFrame},
     notify::Notifier,
-    stream::{self, State, StreamEntry, CONNECTION_ID}
+    session::{Action, ConnectionState, Session},
+    snapshot::{FrameSnapshot, Snapshot},
+    stats::{Counted, Stats},
+    stream::{self, State}
 };
-use futures::{executor, try_ready, prelude::*, stream::{Fuse, Stream}};
+#[cfg(feature = "body-age")]
+use crate::snapshot::BodyAges;
+#[cfg(feature = "frame-history")]
+use crate::{frame::header::RawHeader, history::{Direction, History}};
+use futures::{executor, try_ready, future::{self, Either, Executor}, prelude::*, stream::{Fuse, Stream}, sync::{mpsc, oneshot}};
 use log::{debug, error, trace};
 use parking_lot::{Mutex, MutexGuard};
 use std::{
     cmp::min,
-    collections::{BTreeMap, VecDeque},
+    collections::VecDeque,
     fmt,
     io,
     ops::{Deref, DerefMut},
     sync::Arc,
-    u32,
-    usize
+    time::{Duration, Instant}
 };
-use tokio_codec::Framed;
-use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_codec::{Framed, FramedParts};
+use tokio_io::{io::{read_to_end, write_all}, AsyncRead, AsyncWrite};
+
+/// Initial capacity of the codec's read and write buffers.
+const CODEC_BUFFER_SIZE: usize = 8 * 1024;
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum Mode { Client, Server }
 
 /// Holds the underlying connection.
+///
+/// Outbound frames are sent in the order they have been written. Streams
+/// have no priorities.
 pub struct Connection<T> {
     inner: Arc<Mutex<Inner<T>>>
 }
@@ -62,32 +69,41 @@ where
     T: AsyncRead + AsyncWrite
 {
     pub fn new(res: T, cfg: Config, mode: Mode) -> Self {
+        Connection::with_buffers(res, cfg, mode, Arc::new(GlobalBuffers))
+    }
+
+    fn with_buffers(res: T, cfg: Config, mode: Mode, buffers: Arc<dyn BufferProvider>) -> Self {
         Connection {
-            inner: Arc::new(Mutex::new(Inner::new(res, cfg, mode)))
+            inner: Arc::new(Mutex::new(Inner::new(res, cfg, mode, buffers)))
         }
     }
 
+    /// Create a `Builder` for a connection over the given resource.
+    pub fn builder(res: T, mode: Mode) -> Builder<T> {
+        Builder::new(res, mode)
+    }
+
     /// Open a new outbound stream which is multiplexed over the existing connection.
     ///
     /// This may fail if the underlying connection is already dead (in which case `None` is
     /// returned), or for other reasons, e.g. if the (configurable) maximum number of streams is
     /// already open.
     pub fn open_stream(&self) -> Result<Option<StreamHandle<T>>, ConnectionError> {
-        let mut connection = Use::with(self.inner.lock(), Action::None);
-        if connection.is_dead {
+        let mut connection = Use::with(self.inner.lock(), OnDrop::None);
+        if connection.session.state() >= ConnectionState::Closing || connection.draining_since.is_some() {
             return Ok(None)
         }
-        if connection.streams.len() >= connection.config.max_num_streams {
-            error!("maximum number of streams reached");
-            return Err(ConnectionError::TooManyStreams)
+        if connection.is_congested {
+            return Err(ConnectionError::Congested)
         }
-        let id = connection.next_stream_id()?;
-        let mut frame = Frame::window_update(id, connection.config.receive_window);
-        frame.header_mut().syn();
-        connection.pending.push_back(frame.into_raw());
-        let stream = StreamEntry::new(connection.config.receive_window, DEFAULT_CREDIT);
-        let buffer = stream.buffer.clone();
-        connection.streams.insert(id, stream);
+        if connection.max_pending_opens.map(|max| connection.pending_opens >= max).unwrap_or(false) {
+            return Err(ConnectionError::TooManyPendingOpens)
+        }
+        let id = connection.session.open_stream()?;
+        connection.pending_opens += 1;
+        connection.check_soft_limits(None);
+        let buffer = connection.session.stream(id).expect("stream has just been opened").buffer.clone();
+        connection.stats.streams_opened += 1;
         debug!("outgoing stream {}: {:?}", id, *connection);
         Ok(Some(StreamHandle::new(id, buffer, self.clone())))
     }
@@ -96,45 +112,259 @@ where
     ///
     /// Use `flush` or `close` to force sending of the corresponding protocol frame.
     pub fn shutdown(&self) -> Poll<(), io::Error> {
-        let mut connection = Use::with(self.inner.lock(), Action::None);
-        if connection.is_dead {
+        let mut connection = Use::with(self.inner.lock(), OnDrop::None);
+        if connection.is_dead() {
             return Ok(Async::Ready(()))
         }
-        connection.pending.push_back(Frame::go_away(header::CODE_TERM).into_raw());
+        connection.session.go_away(GoAwayReason::CLOSED);
         Ok(Async::Ready(()))
     }
 
+    /// Send a ping to the remote to measure the round-trip time.
+    ///
+    /// Use `flush` to force sending of the ping. Once answered, the
+    /// round-trip time is reported by `Stats::rtt`. At most a few pings
+    /// may await a reply at any time. Pings which have not been answered
+    /// within a minute are considered lost and do not count.
+    pub fn ping(&self) -> Result<(), ConnectionError> {
+        let mut connection = self.inner.lock();
+        if connection.is_dead() {
+            return Err(ConnectionError::Closed)
+        }
+        if connection.is_congested {
+            return Err(ConnectionError::Congested)
+        }
+        connection.session.ping()?;
+        connection.stats.pings_sent += 1;
+        Ok(())
+    }
+
     /// Closes the underlying connection.
     ///
-    /// Implies flushing any buffered data.
+    /// Unless sent already, a GoAway is sent to the remote first. Implies
+    /// flushing any buffered data.
     pub fn close(&self) -> Poll<(), io::Error> {
-        let mut connection = Use::with(self.inner.lock(), Action::Destroy);
-        if connection.is_dead {
+        let mut connection = Use::with(self.inner.lock(), OnDrop::Destroy);
+        if connection.is_dead() {
             return Ok(Async::Ready(()))
         }
-        if connection.flush_pending()?.is_not_ready() {
-            connection.on_drop(Action::None);
-            return Ok(Async::NotReady)
-        }
-        let result = {
-            let c = &mut *connection;
-            c.resource.close_notify(&mut c.tasks, 0)?
-        };
-        if result.is_not_ready() {
-            connection.on_drop(Action::None)
-        }
+        connection.session.close();
+        let result = connection.poll_close()?;
+        connection.on_drop(OnDrop::None);
         Ok(result)
     }
 
     /// Send any buffered data.
     pub fn flush(&self) -> Poll<(), io::Error> {
-        let mut connection = Use::with(self.inner.lock(), Action::Destroy);
+        let mut connection = Use::with(self.inner.lock(), OnDrop::Destroy);
         let result = connection.flush_pending()?;
-        connection.on_drop(Action::None);
+        connection.on_drop(OnDrop::None);
         Ok(result)
     }
+
+    /// Get the number of outbound streams whose opening frame has not been
+    /// sent yet.
+    pub fn pending_opens(&self) -> usize {
+        self.inner.lock().pending_opens
+    }
+
+    /// Subscribe to this connection's events.
+    ///
+    /// Events are only delivered to subscribers registered before they happen.
+    /// Each subscriber buffers a limited number of events. If it does not keep
+    /// up, further events are dropped for it until it has caught up, so that a
+    /// slow or abandoned subscriber can not make the connection use ever more
+    /// memory.
+    pub fn events(&self) -> mpsc::Receiver<ConnectionEvent> {
+        self.inner.lock().events.subscribe()
+    }
+
+    /// Deliver `event` to this connection's subscribers as
+    /// `ConnectionEvent::Custom`.
+    ///
+    /// Lets extensions report through the same channel as the connection
+    /// itself, so applications have a single stream of events.
+    pub fn publish(&self, event: CustomEvent) {
+        self.inner.lock().events.emit(ConnectionEvent::Custom(event))
+    }
+
+    /// Get a snapshot of this connection's statistics.
+    pub fn stats(&self) -> Stats {
+        self.inner.lock().stats()
+    }
+
+    /// Get a snapshot of this connection's state, e.g. for bug reports.
+    ///
+    /// The bodies of pending frames are redacted.
+    pub fn debug_dump(&self) -> Snapshot {
+        self.inner.lock().snapshot(false)
+    }
+
+    /// Like `debug_dump` but includes the bodies of pending frames.
+    ///
+    /// Beware that these may contain application data.
+    pub fn debug_dump_with_bodies(&self) -> Snapshot {
+        self.inner.lock().snapshot(true)
+    }
+
+    /// Poll for up to `max` new inbound streams at once.
+    ///
+    /// Like `Stream::poll` but amortises processing incoming frames and task
+    /// wakeups over many streams, for servers with high accept rates. Any data
+    /// a stream has been opened with is available for reading immediately.
+    /// Yields `None` once the connection is closed.
+    ///
+    /// A `max` of 0 is treated as 1, i.e. batches are never empty.
+    pub fn poll_incoming_batch(&self, max: usize) -> Poll<Option<Vec<StreamHandle<T>>>, ConnectionError> {
+        let max = max.max(1);
+        let mut connection = Use::with(self.inner.lock(), OnDrop::Destroy);
+        connection.process_incoming()?;
+        if connection.is_dead() {
+            return Ok(Async::Ready(None))
+        }
+        let mut streams = Vec::new();
+        while streams.len() < max {
+            match self.next_incoming(&mut connection) {
+                Some(stream) => streams.push(stream),
+                None => break
+            }
+        }
+        connection.on_drop(OnDrop::None);
+        if streams.is_empty() {
+            return Ok(Async::NotReady)
+        }
+        Ok(Async::Ready(Some(streams)))
+    }
+
+    /// Turn this connection into a stream of inbound streams which ends with
+    /// the reason the connection ended.
+    pub fn incoming(self) -> Incoming<T> {
+        Incoming { connection: self, is_terminated: false }
+    }
+
+    /// Take the next inbound stream which has not been reset in the meantime.
+    fn next_incoming(&self, connection: &mut Inner<T>) -> Option<StreamHandle<T>> {
+        while let Some(id) = connection.incoming.pop_front() {
+            if let Some(stream) = connection.session.stream(id) {
+                debug!("incoming stream {}: {:?}", id, connection);
+                return Some(StreamHandle::new(id, stream.buffer.clone(), self.clone()))
+            }
+        }
+        None
+    }
+
+    /// Register interest in a particular inbound stream.
+    ///
+    /// The returned future resolves to the first inbound stream arriving after
+    /// this call which is selected by `matcher`. This stream is then not yielded
+    /// by this connection's `Stream` implementation. Expectations are matched in
+    /// the order they have been registered. The future drives
+    /// the connection while it is polled and fails with `ConnectionError::Closed`
+    /// if the connection closes before a matching stream arrives.
+    pub fn expect_stream(&self, matcher: Matcher) -> ExpectStream<T> {
+        let (tx, rx) = oneshot::channel();
+        let mut connection = self.inner.lock();
+        if !connection.is_dead() {
+            connection.expected.push((matcher, tx))
+        }
+        ExpectStream { connection: self.clone(), rx }
+    }
+
+    /// Drive this connection and handle every inbound stream.
+    ///
+    /// For each inbound stream the future returned by `handler` is spawned onto
+    /// `executor`. The returned future resolves when the connection ends and fails
+    /// with `ConnectionError::Spawn` if `executor` rejects a stream handler.
+    pub fn serve<E, H, F>(self, executor: E, mut handler: H) -> impl Future<Item=(), Error=ConnectionError>
+    where
+        E: Executor<F::Future>,
+        H: FnMut(StreamHandle<T>) -> F,
+        F: IntoFuture<Item=(), Error=()>
+    {
+        self.for_each(move |stream| {
+            executor.execute(handler(stream).into_future()).map_err(|e| {
+                error!("failed to spawn stream handler: {:?}", e.kind());
+                ConnectionError::Spawn(e.kind())
+            })
+        })
+    }
+
+    /// Send a request on a new stream and resolve to the remote's response.
+    ///
+    /// The stream is half-closed once `body` has been written. The response is
+    /// everything the remote writes until it half-closes the stream in turn,
+    /// e.g. by answering with `serve_requests`. The stream is closed afterwards.
+    pub fn request(&self, body: Bytes) -> impl Future<Item=Bytes, Error=ConnectionError> {
+        let stream = match self.open_stream() {
+            Ok(Some(stream)) => stream,
+            Ok(None) => return Either::A(future::err(ConnectionError::Closed)),
+            Err(e) => return Either::A(future::err(e))
+        };
+        let response = write_all(stream, body)
+            .and_then(|(stream, _)| finish(stream))
+            .and_then(|stream| read_to_end(stream, Vec::new()))
+            .map(|(_, response)| Bytes::from(response))
+            .map_err(ConnectionError::Io);
+        Either::B(response)
+    }
+
+    /// Drive this connection and answer every inbound stream as a request.
+    ///
+    /// The counterpart of `request`: the handler is called with everything
+    /// the remote writes until it half-closes the stream. The response the
+    /// handler resolves to is written back before the stream is half-closed.
+    /// Like `serve`, the handling of each stream is spawned onto `executor`.
+    pub fn serve_requests<E, H, F>(self, executor: E, handler: H) -> impl Future<Item=(), Error=ConnectionError>
+    where
+        T: Send + 'static,
+        E: Executor<RequestHandler>,
+        H: Fn(Bytes) -> F + Send + Sync + 'static,
+        F: IntoFuture<Item=Bytes, Error=()>,
+        F::Future: Send + 'static
+    {
+        let handler = Arc::new(handler);
+        self.serve(executor, move |stream| -> RequestHandler {
+            let id = stream.id;
+            let handler = handler.clone();
+            let handle = read_to_end(stream, Vec::new())
+                .map_err(move |e| debug!("stream {}: failed to read request: {}", id, e))
+                .and_then(move |(stream, request)| {
+                    handler(Bytes::from(request)).into_future().map(|response| (stream, response))
+                })
+                .and_then(move |(stream, response)| {
+                    write_all(stream, response)
+                        .and_then(|(stream, _)| finish(stream))
+                        .map(drop)
+                        .map_err(move |e| debug!("stream {}: failed to write response: {}", id, e))
+                });
+            Box::new(handle)
+        })
+    }
+}
+
+/// Whether `frame` only grants credit to the remote.
+///
+/// Such window updates may be sent ahead of other queued frames, see
+/// `Inner::may_overtake`.
+fn is_credit(frame: &RawFrame) -> bool {
+    frame.header.typ == Type::WindowUpdate && frame.header.flags == Flags(0)
+}
+
+/// Half-close `stream` and resolve to it afterwards.
+fn finish<T>(stream: StreamHandle<T>) -> impl Future<Item=StreamHandle<T>, Error=io::Error>
+where
+    T: AsyncRead + AsyncWrite
+{
+    let mut stream = Some(stream);
+    future::poll_fn(move || {
+        try_ready!(stream.as_mut().expect("finish polled after completion").finish());
+        Ok(Async::Ready(stream.take().expect("finish polled after completion")))
+    })
 }
 
+/// The future handling a single request of `Connection::serve_requests`.
+pub type RequestHandler = Box<dyn Future<Item=(), Error=()> + Send>;
+
 impl<T> Stream for Connection<T>
 where
     T: AsyncRead + AsyncWrite
@@ -143,40 +373,291 @@ where
     type Error = ConnectionError;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut connection = Use::with(self.inner.lock(), Action::Destroy);
+        let mut connection = Use::with(self.inner.lock(), OnDrop::Destroy);
         connection.process_incoming()?;
-        if connection.is_dead {
+        if connection.is_dead() {
             return Ok(Async::Ready(None))
         }
-        while let Some(id) = connection.incoming.pop_front() {
-            let stream =
-                if let Some(stream) = connection.streams.get(&id) {
-                    debug!("incoming stream {}: {:?}", id, *connection);
-                    StreamHandle::new(id, stream.buffer.clone(), self.clone())
-                } else {
-                    continue
-                };
-                connection.on_drop(Action::None);
-                return Ok(Async::Ready(Some(stream)))
-        }
-        connection.on_drop(Action::None);
+        if let Some(stream) = self.next_incoming(&mut connection) {
+            connection.on_drop(OnDrop::None);
+            return Ok(Async::Ready(Some(stream)))
+        }
+        connection.on_drop(OnDrop::None);
         Ok(Async::NotReady)
     }
 }
 
-enum Action { Destroy, None }
+/// Why a connection has ended.
+#[derive(Debug)]
+pub enum EndReason {
+    /// The remote sent a GoAway frame with the given error code.
+    GoAway(u32),
+    /// The connection has been closed locally, e.g. by `Connection::close`.
+    Closed,
+    /// The remote closed the underlying resource.
+    Eof,
+    /// The connection failed. If the error has been returned to someone else
+    /// using the connection, e.g. a stream, this is `ConnectionError::Closed`.
+    Error(ConnectionError)
+}
+
+/// An item of `Incoming`.
+pub enum Inbound<T>
+where
+    T: AsyncRead + AsyncWrite
+{
+    /// A new inbound stream.
+    Stream(StreamHandle<T>),
+    /// The connection has ended. This is the last item.
+    End(EndReason)
+}
+
+/// The inbound streams of a connection, followed by the reason it ended.
+///
+/// Unlike the `Stream` implementation of `Connection`, errors are not
+/// returned but yielded as `EndReason::Error`, so this stream never fails.
+/// After the end reason, it only yields `None`.
+///
+/// See `Connection::incoming`.
+pub struct Incoming<T> {
+    connection: Connection<T>,
+    is_terminated: bool
+}
+
+impl<T> Incoming<T> {
+    /// Whether the end reason has been yielded already.
+    pub fn is_terminated(&self) -> bool {
+        self.is_terminated
+    }
+}
+
+impl<T> Stream for Incoming<T>
+where
+    T: AsyncRead + AsyncWrite
+{
+    type Item = Inbound<T>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.is_terminated {
+            return Ok(Async::Ready(None))
+        }
+        let reason = match self.connection.poll() {
+            Ok(Async::Ready(Some(stream))) => return Ok(Async::Ready(Some(Inbound::Stream(stream)))),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(None)) => {
+                self.connection.inner.lock().end.take().unwrap_or(EndReason::Error(ConnectionError::Closed))
+            }
+            Err(e) => EndReason::Error(e)
+        };
+        debug!("incoming streams ended: {:?}", reason);
+        self.is_terminated = true;
+        Ok(Async::Ready(Some(Inbound::End(reason))))
+    }
+}
+
+/// Selects the stream an `ExpectStream` future resolves to.
+pub enum Matcher {
+    /// The next inbound stream.
+    Next,
+    /// The next inbound stream whose initial data satisfies the predicate.
+    ///
+    /// The initial data is whatever has been received when the first data frame
+    /// of the stream arrives. Until then, inbound streams are not yielded by the
+    /// connection's `Stream` implementation. Streams closed without data are
+    /// matched against an empty slice.
+    FirstBytes(Predicate)
+}
+
+type Predicate = Box<dyn Fn(&[u8]) -> bool + Send>;
+
+/// Find the first expectation matching a stream with the given initial data.
+///
+/// Returns `None` if the data is not known yet but needed to decide and
+/// `Some(None)` if no expectation matches.
+fn find_match<X>(expected: &[(Matcher, X)], data: Option<&[u8]>) -> Option<Option<usize>> {
+    for (i, (m, _)) in expected.iter().enumerate() {
+        match (m, data) {
+            (Matcher::Next, _) => return Some(Some(i)),
+            (Matcher::FirstBytes(f), Some(d)) => if f(d) { return Some(Some(i)) }
+            (Matcher::FirstBytes(_), None) => return None
+        }
+    }
+    Some(None)
+}
+
+/// A future resolving to an expected inbound stream.
+///
+/// See `Connection::expect_stream`.
+pub struct ExpectStream<T> {
+    connection: Connection<T>,
+    rx: oneshot::Receiver<stream::Id>
+}
+
+impl<T> Future for ExpectStream<T>
+where
+    T: AsyncRead + AsyncWrite
+{
+    type Item = StreamHandle<T>;
+    type Error = ConnectionError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut connection = Use::with(self.connection.inner.lock(), OnDrop::Destroy);
+        connection.process_incoming()?;
+        connection.on_drop(OnDrop::None);
+        let id = match self.rx.poll() {
+            Ok(Async::Ready(id)) => id,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(oneshot::Canceled) => return Err(ConnectionError::Closed)
+        };
+        match connection.session.stream(id) {
+            Some(stream) => {
+                debug!("expected stream {}: {:?}", id, *connection);
+                Ok(Async::Ready(StreamHandle::new(id, stream.buffer.clone(), self.connection.clone())))
+            }
+            None => Err(ConnectionError::StreamNotFound(id))
+        }
+    }
+}
+
+impl<T> Drop for ExpectStream<T> {
+    fn drop(&mut self) {
+        // A stream which has been matched but not claimed is handed to the
+        // accept loop, as if this expectation had been dropped earlier.
+        self.rx.close();
+        let claimed = self.rx.try_recv();
+        let mut connection = self.connection.inner.lock();
+        if let Ok(Some(id)) = claimed {
+            if connection.session.stream(id).is_some() {
+                debug!("expected stream {} not claimed", id);
+                connection.incoming.push_front(id)
+            }
+        }
+        // Streams held back for this expectation may be accepted now.
+        if !connection.unmatched.is_empty() {
+            connection.match_incoming()
+        }
+        if !connection.incoming.is_empty() {
+            connection.tasks.notify_all()
+        }
+    }
+}
+
+/// Builds a `Connection` with non-default settings.
+pub struct Builder<T> {
+    resource: T,
+    mode: Mode,
+    config: Config,
+    shutdown_signal: Option<ShutdownSignal>,
+    drain_timeout: Option<Duration>,
+    buffers: Arc<dyn BufferProvider>,
+    ids: Option<Box<dyn IdAllocator>>
+}
+
+type ShutdownSignal = Box<dyn Future<Item=(), Error=()> + Send>;
+
+impl<T> Builder<T>
+where
+    T: AsyncRead + AsyncWrite
+{
+    pub fn new(res: T, mode: Mode) -> Self {
+        Builder {
+            resource: res,
+            mode,
+            config: Config::default(),
+            shutdown_signal: None,
+            drain_timeout: None,
+            buffers: Arc::new(GlobalBuffers),
+            ids: None
+        }
+    }
+
+    /// Set the base configuration.
+    pub fn config(mut self, cfg: Config) -> Self {
+        self.config = cfg;
+        self
+    }
+
+    /// Apply the overrides `provider` has for `peer` to the configuration.
+    ///
+    /// Overrides are resolved against the configuration set so far, i.e. this
+    /// should be called after `config`.
+    pub fn peer<P: ConfigProvider>(mut self, provider: &P, peer: &P::PeerId) -> Self {
+        self.config = provider.config(peer, &self.config);
+        self
+    }
+
+    /// Close the connection gracefully once `signal` resolves or fails.
+    ///
+    /// The connection then refuses new streams by sending a GoAway frame but
+    /// keeps serving the open ones. Once they are gone (or the drain timeout
+    /// has passed, see `drain_timeout`), it flushes all pending frames and
+    /// closes the underlying resource. This happens while the connection is
+    /// polled, e.g. by the accept loop.
+    pub fn shutdown_signal<S>(mut self, signal: S) -> Self
+    where
+        S: IntoFuture<Item=()>,
+        S::Future: Send + 'static
+    {
+        self.shutdown_signal = Some(Box::new(signal.into_future().then(|_| Ok(()))));
+        self
+    }
+
+    /// Close the connection at most `timeout` after the shutdown signal even
+    /// if streams are still open.
+    ///
+    /// The timeout is only checked while the connection is polled. By
+    /// default, the connection waits for all streams to go away.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Allocate buffers with `provider` instead of the global allocator.
+    ///
+    /// A provider may be shared by many connections.
+    pub fn buffer_provider(mut self, provider: Arc<dyn BufferProvider>) -> Self {
+        self.buffers = provider;
+        self
+    }
+
+    /// Choose the IDs of outbound streams with `ids` instead of counting up
+    /// from the first ID of the mode.
+    ///
+    /// See `IdAllocator` for the IDs a connection accepts.
+    pub fn id_allocator(mut self, ids: Box<dyn IdAllocator>) -> Self {
+        self.ids = Some(ids);
+        self
+    }
+
+    /// Create the connection.
+    pub fn build(self) -> Connection<T> {
+        let connection = Connection::with_buffers(self.resource, self.config, self.mode, self.buffers);
+        {
+            let mut inner = connection.inner.lock();
+            inner.shutdown_signal = self.shutdown_signal;
+            inner.drain_timeout = self.drain_timeout;
+            if let Some(ids) = self.ids {
+                inner.session.set_id_allocator(ids)
+            }
+        }
+        connection
+    }
+}
+
+enum OnDrop { Destroy, None }
 
 struct Use<'a, T: 'a> {
     inner: MutexGuard<'a, Inner<T>>,
-    on_drop: Action
+    on_drop: OnDrop
 }
 
 impl<'a, T> Use<'a, T> {
-    fn with(inner: MutexGuard<'a, Inner<T>>, on_drop: Action) -> Self {
+    fn with(inner: MutexGuard<'a, Inner<T>>, on_drop: OnDrop) -> Self {
         Use { inner, on_drop }
     }
 
-    fn on_drop(&mut self, val: Action) {
+    fn on_drop(&mut self, val: OnDrop) {
         self.on_drop = val
     }
 }
@@ -197,297 +678,507 @@ impl<'a, T> DerefMut for Use<'a, T> {
 
 impl<'a, T> Drop for Use<'a, T> {
     fn drop(&mut self) {
-        if let Action::Destroy = self.on_drop {
-            debug!("{:?}: destroying connection", self.inner.mode);
-            self.inner.is_dead = true;
-            self.inner.streams.clear();
-            self.inner.tasks.notify_all()
+        if let OnDrop::Destroy = self.on_drop {
+            debug!("{:?}: destroying connection", self.inner.session.mode());
+            self.inner.terminate(None)
         }
     }
 }
 
 struct Inner<T> {
-    mode: Mode,
-    is_dead: bool,
-    config: Config,
-    streams: BTreeMap<stream::Id, StreamEntry>,
-    resource: executor::Spawn<Fuse<Framed<T, FrameCodec>>>,
+    session: Session,
+    resource: executor::Spawn<Fuse<Framed<Counted<T>, FrameCodec>>>,
     incoming: VecDeque<stream::Id>,
-    pending: VecDeque<RawFrame>,
+    expected: Vec<(Matcher, oneshot::Sender<stream::Id>)>,
+    unmatched: Vec<stream::Id>,
+    shutdown_signal: Option<ShutdownSignal>,
+    drain_timeout: Option<Duration>,
+    /// When the shutdown signal has been triggered.
+    draining_since: Option<Timestamp>,
+    /// Why the connection has ended, unless it failed.
+    end: Option<EndReason>,
+    /// Frames to send and when they have been queued.
+    pending: VecDeque<(Instant, RawFrame)>,
+    /// Window updates to send ahead of `pending`, see `is_credit`.
+    credit: VecDeque<(Instant, RawFrame)>,
+    /// When the frames written since the last flush have been queued.
+    unflushed_queued: Vec<Instant>,
     tasks: Arc<Notifier>,
-    next_id: u32
+    stats: Stats,
+    events: Subscribers,
+    congestion_threshold: Option<Duration>,
+    blocked_since: Option<Timestamp>,
+    is_congested: bool,
+    /// Number of outbound streams whose SYN has not been sent yet.
+    pending_opens: usize,
+    max_pending_opens: Option<usize>,
+    /// When the oldest body handed to the codec since the last flush has been written.
+    #[cfg(feature = "body-age")]
+    unflushed: Option<Instant>,
+    #[cfg(feature = "frame-history")]
+    history: Option<History>
 }
 
 impl<T> fmt::Debug for Inner<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Connection {{ \
-                mode: {:?}, \
-                streams: {}, \
+                session: {:?}, \
                 incoming: {}, \
                 pending: {}, \
-                next_id: {}, \
                 tasks: {} \
             }}",
-            self.mode,
-            self.streams.len(),
+            self.session,
             self.incoming.len(),
-            self.pending.len(),
-            self.next_id,
+            self.pending.len() + self.credit.len(),
             self.tasks.len()
         )
     }
 }
 
+impl<T> Inner<T> {
+    /// Hand inbound streams to the first expectation they match or queue them
+    /// for accepting.
+    ///
+    /// Streams which have not received any data yet are held back as long as
+    /// an expectation needs their data to decide.
+    fn match_incoming(&mut self) {
+        self.expected.retain(|(_, tx)| !tx.is_canceled());
+        let mut i = 0;
+        while i < self.unmatched.len() {
+            let id = self.unmatched[i];
+            let decision = match self.session.stream(id) {
+                Some(stream) => {
+                    let data = stream.buffer.lock();
+                    if data.is_empty() && stream.state() == State::Open {
+                        find_match(&self.expected, None)
+                    } else {
+                        find_match(&self.expected, Some(&data))
+                    }
+                }
+                None => Some(None)
+            };
+            match decision {
+                None => i += 1,
+                Some(m) => {
+                    self.unmatched.remove(i);
+                    if let Some(m) = m {
+                        let (_, tx) = self.expected.remove(m);
+                        if tx.send(id).is_ok() {
+                            continue
+                        }
+                    }
+                    self.incoming.push_back(id)
+                }
+            }
+        }
+    }
+
+    fn is_dead(&self) -> bool {
+        self.session.state() == ConnectionState::Closed
+    }
+
+    /// Mark this connection as dead and wake up everyone waiting on it.
+    ///
+    /// Only the first call has any effect.
+    fn terminate(&mut self, reason: Option<EndReason>) {
+        if self.session.terminate() {
+            self.on_terminated(reason)
+        }
+    }
+
+    /// Clean up after the session has terminated.
+    fn on_terminated(&mut self, reason: Option<EndReason>) {
+        self.end = reason;
+        self.pending.clear();
+        self.credit.clear();
+        self.pending_opens = 0;
+        self.expected.clear();
+        self.unmatched.clear();
+        self.tasks.notify_all()
+    }
+}
+
 impl<T> Inner<T>
 where
     T: AsyncRead + AsyncWrite
 {
-    fn new(resource: T, config: Config, mode: Mode) -> Self {
-        let framed = Framed::new(resource, FrameCodec::new(&config)).fuse();
+    fn new(resource: T, config: Config, mode: Mode, buffers: Arc<dyn BufferProvider>) -> Self {
+        let mut parts = FramedParts::new(Counted::new(resource), FrameCodec::new(&config));
+        parts.read_buf = buffers.alloc(CODEC_BUFFER_SIZE);
+        parts.write_buf = buffers.alloc(CODEC_BUFFER_SIZE);
+        let framed = Framed::from_parts(parts).fuse();
+        let congestion_threshold = config.congestion_threshold;
+        let max_pending_opens = config.max_pending_opens;
+        #[cfg(feature = "frame-history")]
+        let history = if config.frame_history > 0 { Some(History::new(config.frame_history)) } else { None };
+        let mut session = Session::new(config, mode);
+        session.set_buffer_provider(buffers);
         Inner {
-            mode,
-            is_dead: false,
-            config,
-            streams: BTreeMap::new(),
+            session,
             resource: executor::spawn(framed),
             incoming: VecDeque::new(),
+            expected: Vec::new(),
+            unmatched: Vec::new(),
+            shutdown_signal: None,
+            drain_timeout: None,
+            draining_since: None,
+            end: None,
             pending: VecDeque::new(),
+            credit: VecDeque::new(),
+            unflushed_queued: Vec::new(),
             tasks: Arc::new(Notifier::new()),
-            next_id: match mode {
-                Mode::Client => 1,
-                Mode::Server => 2
-            }
+            stats: Stats::default(),
+            events: Subscribers::default(),
+            congestion_threshold,
+            blocked_since: None,
+            is_congested: false,
+            pending_opens: 0,
+            max_pending_opens,
+            #[cfg(feature = "body-age")]
+            unflushed: None,
+            #[cfg(feature = "frame-history")]
+            history
         }
     }
 
-    fn next_stream_id(&mut self) -> Result<stream::Id, ConnectionError> {
-        let proposed = stream::Id::new(self.next_id);
-        self.next_id = self.next_id.checked_add(2).ok_or(ConnectionError::NoMoreStreamIds)?;
-        match self.mode {
-            Mode::Client => assert!(proposed.is_client()),
-            Mode::Server => assert!(proposed.is_server())
+    fn snapshot(&self, with_bodies: bool) -> Snapshot {
+        let mut snapshot = self.session.snapshot(with_bodies);
+        let mut pending = self.credit.iter()
+            .chain(self.pending.iter())
+            .map(|(_, f)| FrameSnapshot::new(f, with_bodies))
+            .collect::<Vec<_>>();
+        pending.append(&mut snapshot.pending);
+        snapshot.pending = pending;
+        snapshot.incoming = self.incoming.len();
+        #[cfg(feature = "body-age")]
+        {
+            snapshot.body_ages.connection_queue = BodyAges::oldest(self.pending.iter().filter(|(_, f)| !f.body.is_empty()).map(|&(t, _)| t));
+            snapshot.body_ages.codec_buffer = self.unflushed.map(|t| t.elapsed())
         }
-        Ok(proposed)
+        snapshot.stats = self.stats();
+        snapshot
     }
 
-    fn is_valid_remote_id(&self, id: stream::Id, ty: Type) -> bool {
-        match ty {
-            Type::Ping | Type::GoAway => return id.is_session(),
-            _ => {}
-        }
-        match self.mode {
-            Mode::Client => id.is_server(),
-            Mode::Server => id.is_client()
-        }
+    /// Get the statistics including the writes to the underlying connection.
+    fn stats(&self) -> Stats {
+        let io = self.resource.get_ref().get_ref().get_ref();
+        let mut stats = self.stats.clone();
+        stats.transport_writes = io.writes;
+        stats.transport_bytes_written = io.bytes_written;
+        stats
     }
 
-    fn flush_pending(&mut self) -> Poll<(), io::Error> {
-        if self.is_dead {
-            return Ok(Async::Ready(()))
+    /// Check if the shutdown signal has been triggered and if so, start
+    /// draining the connection.
+    ///
+    /// Once no streams are left or the drain timeout has passed, the
+    /// connection is closed.
+    fn poll_shutdown_signal(&mut self) {
+        let is_triggered = match self.shutdown_signal.as_mut() {
+            Some(signal) => signal.poll() != Ok(Async::NotReady),
+            None => false
+        };
+        if is_triggered {
+            debug!("{:?}: shutdown signal triggered", self.session.mode());
+            self.shutdown_signal = None;
+            self.session.go_away(GoAwayReason::CLOSED);
+            self.draining_since = Some(self.session.now())
         }
-        try_ready!(self.resource.poll_flush_notify(&self.tasks, 0));
-        while let Some(frame) = self.pending.pop_front() {
-            trace!("{:?}: send: {:?}", self.mode, frame.header);
-            if let AsyncSink::NotReady(frame) = self.resource.start_send_notify(frame, &self.tasks, 0)? {
-                self.pending.push_front(frame);
-                return Ok(Async::NotReady)
+        let since = match self.draining_since {
+            Some(since) => since,
+            None => return
+        };
+        let now = self.session.now();
+        let is_expired = match (now.duration_since(since), self.drain_timeout) {
+            (Some(drained), Some(timeout)) => drained >= timeout,
+            (Some(_), None) => false,
+            (None, _) => {
+                // The clock has jumped, start over.
+                self.draining_since = Some(now);
+                false
             }
+        };
+        if is_expired || self.session.num_streams() == 0 {
+            debug!("{:?}: drained, closing (timeout expired: {})", self.session.mode(), is_expired);
+            self.session.close()
         }
-        try_ready!(self.resource.poll_flush_notify(&self.tasks, 0));
+    }
+
+    /// Send all pending frames, including the GoAway, and close the resource.
+    ///
+    /// Only to be called while the session is closing.
+    fn poll_close(&mut self) -> Poll<(), io::Error> {
+        debug_assert_eq!(ConnectionState::Closing, self.session.state());
+        try_ready!(self.flush_pending());
+        try_ready!(self.resource.close_notify(&self.tasks, 0));
+        debug!("{:?}: connection closed", self.session.mode());
+        self.terminate(Some(EndReason::Closed));
         Ok(Async::Ready(()))
     }
 
-    fn process_incoming(&mut self) -> Poll<(), ConnectionError> {
-        if self.is_dead {
-            return Ok(Async::Ready(()))
+    /// Reset a stream on behalf of the local application.
+    ///
+    /// If the stream's SYN is still waiting to be sent, the remote never learns
+    /// about the stream and the stream is abandoned instead.
+    fn reset(&mut self, id: stream::Id) {
+        let actions = self.session.poll_actions();
+        self.apply(actions);
+        let is_unannounced = self.pending.iter()
+            .any(|(_, f)| f.header.stream_id == id && f.header.flags.contains(SYN));
+        if is_unannounced {
+            self.pending.retain(|(_, f)| f.header.stream_id != id);
+            self.credit.retain(|(_, f)| f.header.stream_id != id);
+            self.pending_opens -= 1;
+            self.session.abandon(id)
+        } else {
+            self.session.reset(id)
         }
-        loop {
-            if !self.pending.is_empty() && self.flush_pending()?.is_not_ready() {
-                self.tasks.insert_current();
-                return Ok(Async::NotReady)
-            }
-            match self.resource.poll_stream_notify(&self.tasks, 0)? {
-                Async::Ready(Some(frame)) => {
-                    trace!("{:?}: recv: {:?}", self.mode, frame.header);
-                    let response = match frame.dyn_type() {
-                        Type::Data =>
-                            self.on_data(&Frame::assert(frame))?.map(Frame::into_raw),
-                        Type::WindowUpdate =>
-                            self.on_window_update(&Frame::assert(frame))?.map(Frame::into_raw),
-                        Type::Ping =>
-                            self.on_ping(&Frame::assert(frame)).map(Frame::into_raw),
-                        Type::GoAway => {
-                            self.is_dead = true;
-                            self.streams.clear();
-                            self.tasks.notify_all();
-                            return Ok(Async::Ready(()))
-                        }
-                    };
-                    if let Some(frame) = response {
-                        self.pending.push_back(frame)
-                    }
-                    self.tasks.notify_all();
-                }
-                Async::Ready(None) => {
-                    trace!("{:?}: eof: {:?}", self.mode, self);
-                    self.is_dead = true;
-                    self.streams.clear();
-                    self.tasks.notify_all();
-                    return Ok(Async::Ready(()))
-                }
-                Async::NotReady => {
-                    self.tasks.insert_current();
-                    return Ok(Async::NotReady)
-                }
-            }
+        if self.draining_since.is_some() && self.session.num_streams() == 0 {
+            // Let the connection close now that it is drained.
+            self.tasks.notify_all()
         }
     }
 
-    fn on_data(&mut self, frame: &Frame<Data>) -> Result<Option<Frame<GoAway>>, ConnectionError> {
-        let stream_id = frame.header().id();
-
-        if frame.header().flags().contains(RST) { // stream reset
-            debug!("received reset for stream {}", stream_id);
-            self.streams.remove(&stream_id);
-            return Ok(None)
+    /// Add the header of a frame we have sent to the frame history, if enabled.
+    ///
+    /// Panics with the recorded headers if an ordering invariant is violated,
+    /// as this is a bug of ours.
+    #[cfg(feature = "frame-history")]
+    fn record_sent(&mut self, header: &RawHeader) {
+        if let Some(history) = self.history.as_mut() {
+            if let Err(e) = history.record(Direction::Sent, header) {
+                panic!("{:?}: frame order violated: {}\n{}", self.session.mode(), e, history)
+            }
         }
+    }
 
-        let is_finish = frame.header().flags().contains(FIN); // half-close
-
-        if frame.header().flags().contains(SYN) { // new stream
-            if !self.is_valid_remote_id(stream_id, Type::Data) {
-                error!("invalid stream id {}", stream_id);
-                return Ok(Some(Frame::go_away(ECODE_PROTO)))
-            }
-            if frame.body().len() > DEFAULT_CREDIT as usize {
-                error!("initial data exceeds default credit");
-                return Ok(Some(Frame::go_away(ECODE_PROTO)))
-            }
-            if self.streams.contains_key(&stream_id) {
-                error!("stream {} already exists", stream_id);
-                return Ok(Some(Frame::go_away(ECODE_PROTO)))
-            }
-            if self.streams.len() == self.config.max_num_streams {
-                error!("maximum number of streams reached");
-                return Ok(Some(Frame::go_away(ECODE_INTERNAL)))
-            }
-            let mut stream = StreamEntry::new(self.config.receive_window, DEFAULT_CREDIT);
-            if is_finish {
-                stream.update_state(State::RecvClosed)
+    /// Add the header of a received frame to the frame history, if enabled.
+    ///
+    /// If an ordering invariant is violated, the recorded headers are logged
+    /// and an error is returned which ends the connection.
+    #[cfg(feature = "frame-history")]
+    fn record_received(&mut self, header: &RawHeader) -> Result<(), ConnectionError> {
+        if let Some(history) = self.history.as_mut() {
+            if let Err(e) = history.record(Direction::Received, header) {
+                error!("{:?}: frame order violated: {}\n{}", self.session.mode(), e, history);
+                return Err(ConnectionError::FrameOrder(e))
             }
-            stream.window = stream.window.saturating_sub(frame.body().len() as u32);
-            stream.buffer.lock().extend(frame.body());
-            self.streams.insert(stream_id, stream);
-            self.incoming.push_back(stream_id);
-            return Ok(None)
         }
+        Ok(())
+    }
 
-        let reset_stream =
-            if let Some(stream) = self.streams.get_mut(&stream_id) {
-                if frame.body().len() > stream.window as usize {
-                    error!("frame body larger than window of stream {}", stream_id);
-                    return Ok(Some(Frame::go_away(ECODE_PROTO)))
+    /// Carry out the actions of the protocol session.
+    fn apply(&mut self, actions: Vec<Action>) {
+        for action in actions {
+            match action {
+                Action::Send(frame, queued) => {
+                    if self.may_overtake(&frame) {
+                        self.credit.push_back((queued, frame))
+                    } else {
+                        self.pending.push_back((queued, frame))
+                    }
                 }
-                if is_finish {
-                    stream.update_state(State::RecvClosed)
+                Action::Incoming(id) => {
+                    self.stats.streams_accepted += 1;
+                    self.unmatched.push(id);
+                    self.match_incoming()
                 }
-                if stream.buffer.lock().len() >= self.config.max_buffer_size {
-                    error!("buffer of stream {} grows beyond limit", stream_id);
-                    true
-                } else {
-                    stream.window = stream.window.saturating_sub(frame.body().len() as u32);
-                    stream.buffer.lock().extend(frame.body());
-                    if stream.window == 0 && self.config.window_update_mode == WindowUpdateMode::OnReceive {
-                        trace!("{:?}: stream {}: sending window update", self.mode, stream_id);
-                        let frame = Frame::window_update(stream_id, self.config.receive_window);
-                        self.pending.push_back(frame.into_raw());
-                        stream.window = self.config.receive_window
-                    }
-                    false
+                Action::Pong(rtt) => {
+                    trace!("{:?}: rtt: {:?}", self.session.mode(), rtt);
+                    self.stats.rtt = Some(rtt)
                 }
-            } else {
-                false
-            };
-
-        if reset_stream {
-            self.reset(stream_id)
+                Action::UnexpectedPong(nonce) => {
+                    debug!("{:?}: received pong with unknown nonce {}", self.session.mode(), nonce);
+                    self.stats.unexpected_pongs += 1
+                }
+                Action::GoAway(reason) => self.events.emit(ConnectionEvent::GoAwaySent(reason)),
+                Action::Terminate(code) => {
+                    debug!("{:?}: remote sent go away (code {})", self.session.mode(), code);
+                    self.on_terminated(Some(EndReason::GoAway(code)))
+                }
+            }
         }
-
-        Ok(None)
     }
 
-    fn on_window_update(&mut self, frame: &Frame<WindowUpdate>) -> Result<Option<Frame<GoAway>>, ConnectionError> {
-        let stream_id = frame.header().id();
+    /// Whether `frame` may be sent ahead of all other queued frames.
+    ///
+    /// If the transport backs up, e.g. because the connection is congested and
+    /// stream writes are paused, credit for streams the application reads must
+    /// not wait behind our data frames. Otherwise both endpoints may stall,
+    /// each waiting for the other's credit before reading. Only plain window
+    /// updates of streams whose SYN is not queued any more qualify, so a
+    /// stream is never credited before the remote knows about it. None are
+    /// queued after a stream's reset.
+    fn may_overtake(&self, frame: &RawFrame) -> bool {
+        let id = frame.header.stream_id;
+        is_credit(frame) && !self.pending.iter().any(|(_, f)| f.header.stream_id == id && f.header.flags.contains(SYN))
+    }
 
-        if frame.header().flags().contains(RST) { // stream reset
-            debug!("received reset for stream {}", stream_id);
-            self.streams.remove(&stream_id);
-            return Ok(None)
+    /// Report configured limits which are about to be reached.
+    fn check_soft_limits(&mut self, id: Option<stream::Id>) {
+        for limit in self.session.soft_limits(id) {
+            debug!("{:?}: approaching limit: {:?}", self.session.mode(), limit);
+            self.events.emit(ConnectionEvent::SoftLimit(limit))
         }
+    }
 
-        let is_finish = frame.header().flags().contains(FIN); // half-close
+    fn flush_pending(&mut self) -> Poll<(), io::Error> {
+        let result = self.send_pending()?;
+        self.update_congestion(result.is_ready());
+        Ok(result)
+    }
 
-        if frame.header().flags().contains(SYN) { // new stream
-            if !self.is_valid_remote_id(stream_id, Type::WindowUpdate) {
-                error!("invalid stream id {}", stream_id);
-                return Ok(Some(Frame::go_away(ECODE_PROTO)))
-            }
-            if self.streams.contains_key(&stream_id) {
-                error!("stream {} already exists", stream_id);
-                return Ok(Some(Frame::go_away(ECODE_PROTO)))
+    /// Track for how long pending frames could not be written and report
+    /// congestion once this exceeds the configured threshold.
+    ///
+    /// If the clock jumps, e.g. because the system has been suspended, the
+    /// time blocked is measured anew.
+    fn update_congestion(&mut self, is_flushed: bool) {
+        if is_flushed {
+            self.blocked_since = None;
+            if self.is_congested {
+                debug!("{:?}: congestion is over", self.session.mode());
+                self.is_congested = false;
+                self.events.emit(ConnectionEvent::Decongested)
             }
-            if self.streams.len() == self.config.max_num_streams {
-                error!("maximum number of streams reached");
-                return Ok(Some(Frame::go_away(ECODE_INTERNAL)))
+            return
+        }
+        let now = self.session.now();
+        let blocked = match self.blocked_since.and_then(|t| now.duration_since(t)) {
+            Some(d) => d,
+            None => {
+                self.blocked_since = Some(now);
+                Duration::from_secs(0)
             }
-            let mut stream = StreamEntry::new(self.config.receive_window, frame.header().credit());
-            if is_finish {
-                stream.update_state(State::RecvClosed)
+        };
+        if let Some(threshold) = self.congestion_threshold {
+            if !self.is_congested && blocked >= threshold {
+                debug!("{:?}: congested for {:?}", self.session.mode(), blocked);
+                self.is_congested = true;
+                self.events.emit(ConnectionEvent::Congested(blocked))
             }
-            self.streams.insert(stream_id, stream);
-            self.incoming.push_back(stream_id);
-            return Ok(None)
         }
+    }
 
-        if let Some(stream) = self.streams.get_mut(&stream_id) {
-            stream.credit += frame.header().credit();
-            if is_finish {
-                stream.update_state(State::RecvClosed)
+    fn send_pending(&mut self) -> Poll<(), io::Error> {
+        if self.is_dead() {
+            return Ok(Async::Ready(()))
+        }
+        let actions = self.session.poll_actions();
+        self.apply(actions);
+        try_ready!(self.resource.poll_flush_notify(&self.tasks, 0));
+        self.on_flushed();
+        loop {
+            let (is_credit, (queued, frame)) = match self.credit.pop_front() {
+                Some(entry) => (true, entry),
+                None => match self.pending.pop_front() {
+                    Some(entry) => (false, entry),
+                    None => break
+                }
+            };
+            trace!("{:?}: send: {:?}", self.session.mode(), frame.header);
+            let len = frame.body.len() as u64;
+            let is_reset = frame.header.flags.contains(RST);
+            let is_open = frame.header.typ != Type::Ping && frame.header.flags.contains(SYN);
+            #[cfg(feature = "frame-history")]
+            let header = frame.header.clone();
+            #[cfg(feature = "body-age")]
+            let has_body = !frame.body.is_empty();
+            if let AsyncSink::NotReady(frame) = self.resource.start_send_notify(frame, &self.tasks, 0)? {
+                let queue = if is_credit { &mut self.credit } else { &mut self.pending };
+                queue.push_front((queued, frame));
+                return Ok(Async::NotReady)
+            }
+            self.unflushed_queued.push(queued);
+            #[cfg(feature = "frame-history")]
+            self.record_sent(&header);
+            #[cfg(feature = "body-age")]
+            {
+                if has_body {
+                    self.unflushed = Some(self.unflushed.map_or(queued, |t| min(t, queued)))
+                }
+            }
+            self.stats.frames_sent += 1;
+            self.stats.bytes_sent += len;
+            if is_reset {
+                self.stats.resets_sent += 1
+            }
+            if is_open {
+                self.pending_opens -= 1
             }
         }
-
-        Ok(None)
+        try_ready!(self.resource.poll_flush_notify(&self.tasks, 0));
+        self.on_flushed();
+        Ok(Async::Ready(()))
     }
 
-    fn on_ping(&mut self, frame: &Frame<Ping>) -> Option<Frame<Ping>> {
-        let stream_id = frame.header().id();
-
-        if frame.header().flags().contains(ACK) { // pong
-            return None
+    /// Account for the frames which have reached the underlying connection.
+    fn on_flushed(&mut self) {
+        #[cfg(feature = "body-age")]
+        {
+            self.unflushed = None
         }
-
-        if stream_id == CONNECTION_ID || self.streams.contains_key(&stream_id) {
-            let mut hdr = Header::ping(frame.header().nonce());
-            hdr.ack();
-            return Some(Frame::new(hdr))
+        let now = Instant::now();
+        for queued in self.unflushed_queued.drain(..) {
+            self.stats.queue_latency.record(now.duration_since(queued))
         }
-
-        debug!("received ping for unknown stream {}", stream_id);
-        None
     }
 
-    fn reset(&mut self, id: stream::Id) {
-        if self.streams.remove(&id).is_none() {
-            return ()
+    fn process_incoming(&mut self) -> Poll<(), ConnectionError> {
+        if self.is_dead() {
+            return Ok(Async::Ready(()))
         }
-        if self.is_dead {
-            return ()
+        loop {
+            // Checked on every iteration as received frames may close the
+            // last stream of a draining connection.
+            self.poll_shutdown_signal();
+            if self.session.state() == ConnectionState::Closing {
+                if self.poll_close()?.is_not_ready() {
+                    self.tasks.insert_current();
+                    return Ok(Async::NotReady)
+                }
+                return Ok(Async::Ready(()))
+            }
+            let actions = self.session.poll_actions();
+            self.apply(actions);
+            let has_pending = !self.pending.is_empty() || !self.credit.is_empty();
+            if has_pending && self.flush_pending()?.is_not_ready() && !self.is_congested {
+                self.tasks.insert_current();
+                return Ok(Async::NotReady)
+            }
+            match self.resource.poll_stream_notify(&self.tasks, 0)? {
+                Async::Ready(Some(frame)) => {
+                    trace!("{:?}: recv: {:?}", self.session.mode(), frame.header);
+                    #[cfg(feature = "frame-history")]
+                    self.record_received(&frame.header)?;
+                    self.stats.frames_received += 1;
+                    self.stats.bytes_received += frame.body.len() as u64;
+                    if frame.header.flags.contains(RST) {
+                        self.stats.resets_received += 1
+                    }
+                    let data_id = if frame.header.typ == Type::Data { Some(frame.header.stream_id) } else { None };
+                    let actions = self.session.handle_frame(frame);
+                    self.apply(actions);
+                    self.check_soft_limits(data_id);
+                    if !self.unmatched.is_empty() {
+                        self.match_incoming()
+                    }
+                    if self.is_dead() {
+                        return Ok(Async::Ready(()))
+                    }
+                    self.tasks.notify_all();
+                }
+                Async::Ready(None) => {
+                    trace!("{:?}: eof: {:?}", self.session.mode(), self);
+                    self.terminate(Some(EndReason::Eof));
+                    return Ok(Async::Ready(()))
+                }
+                Async::NotReady => {
+                    self.tasks.insert_current();
+                    return Ok(Async::NotReady)
+                }
+            }
         }
-        debug!("resetting stream {}: {:?}", id, self);
-        let mut header = Header::data(id, 0);
-        header.rst();
-        let frame = Frame::new(header).into_raw();
-        self.pending.push_back(frame)
     }
 }
 
@@ -498,7 +1189,9 @@ where
 {
     id: stream::Id,
     buffer: Arc<Mutex<BytesMut>>,
-    connection: Connection<T>
+    connection: Connection<T>,
+    /// Bytes queued by `write_flush` whose flush has not completed yet.
+    unflushed: Option<usize>
 }
 
 impl<T> StreamHandle<T>
@@ -506,12 +1199,107 @@ where
     T: AsyncRead + AsyncWrite
 {
     fn new(id: stream::Id, buffer: Arc<Mutex<BytesMut>>, conn: Connection<T>) -> Self {
-        StreamHandle { id, buffer, connection: conn }
+        StreamHandle { id, buffer, connection: conn, unflushed: None }
     }
 
     /// Report how much sending credit this stream has available.
     pub fn credit(&self) -> Option<u32> {
-        self.connection.inner.lock().streams.get(&self.id).map(|s| s.credit)
+        self.connection.inner.lock().session.stream(self.id).map(|s| s.credit)
+    }
+
+    /// Write `buf` and immediately flush it to the underlying connection.
+    ///
+    /// Meant for latency-critical messages which must not wait for other frames.
+    /// The data frame is queued and flushed without releasing the connection, so
+    /// no other frame can be queued in between. If previously queued frames can
+    /// not be flushed first, nothing is written and `NotReady` is returned.
+    ///
+    /// `Ready(n)` is only returned once the frame has been flushed. If this does
+    /// not complete right away, the frame stays queued and `NotReady` is
+    /// returned. Call again, with the same `buf`, to complete the flush; the
+    /// frame is not written a second time.
+    pub fn write_flush(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        let mut inner = Use::with(self.connection.inner.lock(), OnDrop::Destroy);
+        if let Some(n) = self.unflushed {
+            if inner.flush_pending()?.is_not_ready() {
+                inner.tasks.insert_current();
+                inner.on_drop(OnDrop::None);
+                return Ok(Async::NotReady)
+            }
+            self.unflushed = None;
+            inner.on_drop(OnDrop::None);
+            return Ok(Async::Ready(n))
+        }
+        if inner.flush_pending()?.is_not_ready() {
+            inner.tasks.insert_current();
+            inner.on_drop(OnDrop::None);
+            return Ok(Async::NotReady)
+        }
+        let n = match self.enqueue(&mut inner, buf) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e)
+        };
+        if inner.flush_pending()?.is_not_ready() {
+            inner.tasks.insert_current();
+            inner.on_drop(OnDrop::None);
+            self.unflushed = Some(n);
+            return Ok(Async::NotReady)
+        }
+        inner.on_drop(OnDrop::None);
+        Ok(Async::Ready(n))
+    }
+
+    /// Half-close this stream.
+    ///
+    /// The remote reads EOF once it has received everything written before,
+    /// but may continue to write to this stream. Unlike `AsyncWrite::shutdown`,
+    /// which resets the stream, nothing is discarded.
+    pub fn finish(&mut self) -> Poll<(), io::Error> {
+        let mut connection = Use::with(self.connection.inner.lock(), OnDrop::Destroy);
+        if let Err(e) = connection.session.finish(self.id) {
+            connection.on_drop(OnDrop::None);
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, e))
+        }
+        let result = connection.flush_pending()?;
+        connection.on_drop(OnDrop::None);
+        Ok(result)
+    }
+
+    /// Queue a data frame with as much of `buf` as the stream's credit allows.
+    fn enqueue(&self, inner: &mut Use<T>, buf: &[u8]) -> io::Result<usize> {
+        match inner.process_incoming() {
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(())) => {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "connection is closed"))
+            }
+        }
+        if inner.is_congested {
+            inner.tasks.insert_current();
+            inner.on_drop(OnDrop::None);
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+        match inner.session.stream(self.id).map(|s| (s.state(), s.credit)) {
+            Some((State::SendClosed, _)) | Some((State::Closed, _)) => {
+                inner.on_drop(OnDrop::None);
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream is closed for writing"))
+            }
+            Some((_, 0)) => {
+                inner.tasks.insert_current();
+                inner.on_drop(OnDrop::None);
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+            Some(_) => {
+                let n = inner.session.send_data(self.id, buf).expect("stream has not been removed");
+                Ok(n)
+            }
+            None => {
+                debug!("stream {} is gone, cannot write", self.id);
+                inner.on_drop(OnDrop::None);
+                Err(io::Error::new(io::ErrorKind::WriteZero, "stream is closed"))
+            }
+        }
     }
 }
 
@@ -530,7 +1318,7 @@ where
     T: AsyncRead + AsyncWrite
 {
     fn read(&mut self, buf: &mut[u8]) -> io::Result<usize> {
-        let mut inner = Use::with(self.connection.inner.lock(), Action::Destroy);
+        let mut inner = Use::with(self.connection.inner.lock(), OnDrop::Destroy);
         loop {
             {
                 let mut bytes = self.buffer.lock();
@@ -538,25 +1326,25 @@ where
                     let n = min(bytes.len(), buf.len());
                     let b = bytes.split_to(n);
                     (&mut buf[0..n]).copy_from_slice(&b);
-                    inner.on_drop(Action::None);
+                    if inner.session.on_read(self.id, n) {
+                        if let Err(e) = inner.flush_pending() {
+                            debug!("stream {}: failed to flush window update: {}", self.id, e)
+                        }
+                    }
+                    inner.on_drop(OnDrop::None);
                     return Ok(n)
                 }
-                if !inner.streams.contains_key(&self.id) {
-                    debug!("stream {} is gone, cannot read", self.id);
-                    inner.on_drop(Action::None);
-                    return Ok(0) // stream has been reset
-                }
-            }
-
-            if inner.config.window_update_mode == WindowUpdateMode::OnRead {
-                let inner = &mut *inner;
-                if let Some(stream) = inner.streams.get_mut(&self.id) {
-                    if stream.window == 0 {
-                        trace!("{:?}: read: stream {}: sending window update", inner.mode, self.id);
-                        let frame = Frame::window_update(self.id, inner.config.receive_window);
-                        inner.pending.push_back(frame.into_raw());
-                        stream.window = inner.config.receive_window
+                match inner.session.stream(self.id).map(|s| s.state()) {
+                    None => {
+                        debug!("stream {} is gone, cannot read", self.id);
+                        inner.on_drop(OnDrop::None);
+                        return Ok(0) // stream has been reset
                     }
+                    Some(State::RecvClosed) | Some(State::Closed) => {
+                        inner.on_drop(OnDrop::None);
+                        return Ok(0) // remote has half-closed the stream
+                    }
+                    Some(State::Open) | Some(State::SendClosed) => {}
                 }
             }
 
@@ -566,7 +1354,7 @@ where
                     if !self.buffer.lock().is_empty() {
                         continue
                     }
-                    inner.on_drop(Action::None);
+                    inner.on_drop(OnDrop::None);
                     return Err(io::ErrorKind::WouldBlock.into())
                 }
                 Ok(Async::Ready(())) => { // connection is dead
@@ -586,49 +1374,22 @@ where
     T: AsyncRead + AsyncWrite
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut inner = Use::with(self.connection.inner.lock(), Action::Destroy);
-        match inner.process_incoming() {
-            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
-            Ok(Async::NotReady) => {}
-            Ok(Async::Ready(())) => {
-                return Err(io::Error::new(io::ErrorKind::WriteZero, "connection is closed"))
-            }
-        }
-        let frame = match inner.streams.get(&self.id).map(|s| s.credit) {
-            Some(0) => {
-                inner.tasks.insert_current();
-                inner.on_drop(Action::None);
-                return Err(io::ErrorKind::WouldBlock.into())
-            }
-            Some(n) => {
-                let k = min(n as usize, buf.len());
-                let b = (&buf[0..k]).into();
-                let s = inner.streams.get_mut(&self.id).expect("stream has not been removed");
-                s.credit = s.credit.saturating_sub(k as u32);
-                Frame::data(self.id, b).into_raw()
-            }
-            None => {
-                debug!("stream {} is gone, cannot write", self.id);
-                inner.on_drop(Action::None);
-                return Err(io::Error::new(io::ErrorKind::WriteZero, "stream is closed"))
-            }
-        };
-        let n = frame.body.len();
-        inner.pending.push_back(frame);
-        inner.on_drop(Action::None);
+        let mut inner = Use::with(self.connection.inner.lock(), OnDrop::Destroy);
+        let n = self.enqueue(&mut inner, buf)?;
+        inner.on_drop(OnDrop::None);
         Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let mut inner = Use::with(self.connection.inner.lock(), Action::Destroy);
+        let mut inner = Use::with(self.connection.inner.lock(), OnDrop::Destroy);
         match inner.flush_pending() {
             Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
             Ok(Async::NotReady) => {
-                inner.on_drop(Action::None);
+                inner.on_drop(OnDrop::None);
                 Err(io::ErrorKind::WouldBlock.into())
             }
             Ok(Async::Ready(())) => {
-                inner.on_drop(Action::None);
+                inner.on_drop(OnDrop::None);
                 Ok(())
             }
         }
@@ -640,19 +1401,588 @@ where
     T: AsyncRead + AsyncWrite
 {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        let mut connection = Use::with(self.connection.inner.lock(), Action::Destroy);
+        let mut connection = Use::with(self.connection.inner.lock(), OnDrop::Destroy);
         connection.reset(self.id);
         match connection.flush_pending() {
             Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
             Ok(Async::NotReady) => {
-                connection.on_drop(Action::None);
+                connection.on_drop(OnDrop::None);
                 Ok(Async::NotReady)
             }
             Ok(Async::Ready(())) => {
-                connection.on_drop(Action::None);
+                connection.on_drop(OnDrop::None);
                 Ok(Async::Ready(()))
             }
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::{memory, DEFAULT_CREDIT, HEADER_SIZE, OpenFrame, event::{SoftLimit, MAX_BUFFERED_EVENTS}, frame::{header, Frame}};
+    use futures::{executor, future};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    type Remote = Framed<memory::Endpoint, FrameCodec>;
+
+    /// Create a server connection and a remote speaking raw frames to it.
+    fn server() -> (Connection<memory::Endpoint>, Remote) {
+        let (a, b) = memory::pair();
+        (Connection::new(a, Config::default(), Mode::Server), Framed::new(b, FrameCodec::default()))
+    }
+
+    /// Call `f` once from within a task.
+    fn poll_once<F: FnMut() -> R, R>(mut f: F) -> R {
+        executor::spawn(future::lazy(|| Ok::<_, ()>(f()))).wait_future().unwrap()
+    }
+
+    fn send(remote: Remote, frame: RawFrame) -> Remote {
+        remote.send(frame).wait().unwrap()
+    }
+
+    fn recv(remote: Remote) -> (RawFrame, Remote) {
+        match remote.into_future().wait() {
+            Ok((Some(frame), remote)) => (frame, remote),
+            Ok((None, _)) => panic!("unexpected eof"),
+            Err((e, _)) => panic!("decode error: {}", e)
+        }
+    }
+
+    fn syn(id: u32) -> RawFrame {
+        let mut frame = Frame::window_update(stream::Id::new(id), DEFAULT_CREDIT);
+        frame.header_mut().syn();
+        frame.into_raw()
+    }
+
+    fn data(id: u32, body: &'static str) -> RawFrame {
+        Frame::data(stream::Id::new(id), Bytes::from(body)).into_raw()
+    }
+
+    fn is_reset(frame: &RawFrame, id: u32) -> bool {
+        frame.header.stream_id == stream::Id::new(id) && frame.header.flags.contains(RST)
+    }
+
+    #[test]
+    fn dropping_unannounced_stream_sends_nothing() {
+        let (a, b) = memory::pair();
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        let remote = Framed::new(b, FrameCodec::default());
+        drop(conn.open_stream().unwrap().unwrap()); // before its SYN is flushed
+        let stream = conn.open_stream().unwrap().unwrap();
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+
+        let (frame, mut remote) = recv(remote);
+        assert_eq!(stream::Id::new(1), frame.header.stream_id); // ID has been reused
+        assert!(frame.header.flags.contains(SYN));
+        assert!(poll_once(|| remote.poll()).unwrap().is_not_ready());
+        drop(stream)
+    }
+
+    #[test]
+    fn open_connection_ignores_unknown_streams() {
+        let (mut conn, remote) = server();
+        let mut remote = send(remote, data(1, "hello"));
+        assert!(poll_once(|| conn.poll()).unwrap().is_not_ready());
+        assert!(poll_once(|| remote.poll()).unwrap().is_not_ready())
+    }
+
+    #[test]
+    fn draining_connection_resets_closed_streams() {
+        let (mut conn, remote) = server();
+        let remote = send(remote, syn(1));
+        match poll_once(|| conn.poll()).unwrap() {
+            Async::Ready(Some(stream)) => drop(stream), // closes the stream
+            _ => panic!("expected inbound stream")
+        }
+        conn.shutdown().unwrap();
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+
+        let remote = send(remote, data(1, "still writing"));
+        assert!(poll_once(|| conn.poll()).unwrap().is_not_ready());
+
+        let (frame, remote) = recv(remote);
+        assert!(is_reset(&frame, 1));
+        let (frame, remote) = recv(remote);
+        assert_eq!(Type::GoAway, frame.header.typ);
+        let (frame, _) = recv(remote);
+        assert!(is_reset(&frame, 1))
+    }
+
+    #[test]
+    fn write_flush_is_not_ready_until_flushed() {
+        let (a, b) = memory::pair_with_capacity(HEADER_SIZE + 4);
+        let client = Connection::new(a, Config::default(), Mode::Client);
+        let mut remote = Framed::new(b, FrameCodec::default());
+        let mut stream = client.open_stream().unwrap().unwrap();
+
+        // The opening frame fits, the data frame does not.
+        assert!(poll_once(|| stream.write_flush(b"hello")).unwrap().is_not_ready());
+        assert!(poll_once(|| stream.write_flush(b"hello")).unwrap().is_not_ready());
+        let (open, r) = recv(remote);
+        assert!(open.header.flags.contains(SYN));
+        remote = r;
+        assert_eq!(Async::Ready(5), poll_once(|| stream.write_flush(b"hello")).unwrap());
+        let (frame, _remote) = recv(remote);
+        assert_eq!(&b"hello"[..], &frame.body[..]);
+        assert_eq!(2, client.stats().frames_sent(), "written once")
+    }
+
+    #[test]
+    fn batches_of_zero_hold_one_stream() {
+        let (conn, remote) = server();
+        let _remote = send(send(remote, syn(1)), syn(3));
+        match poll_once(|| conn.poll_incoming_batch(0)).unwrap() {
+            Async::Ready(Some(streams)) => assert_eq!(1, streams.len()),
+            _ => panic!("expected a batch")
+        }
+    }
+
+    #[test]
+    fn streams_held_for_dropped_expectations_are_accepted() {
+        let (mut conn, remote) = server();
+        let expected = conn.expect_stream(Matcher::FirstBytes(Box::new(|data| data.starts_with(b"hello"))));
+        let _remote = send(remote, syn(1)); // the remote waits for us to speak first
+        assert!(poll_once(|| conn.poll()).unwrap().is_not_ready());
+        drop(expected);
+        match poll_once(|| conn.poll()).unwrap() {
+            Async::Ready(Some(stream)) => assert_eq!(stream::Id::new(1), stream.id),
+            _ => panic!("expected inbound stream")
+        }
+    }
+
+    #[test]
+    fn unclaimed_expected_streams_are_accepted() {
+        let (mut conn, remote) = server();
+        let expected = conn.expect_stream(Matcher::Next);
+        let _remote = send(remote, syn(1));
+        assert!(poll_once(|| conn.poll()).unwrap().is_not_ready()); // matched
+        drop(expected);
+        match poll_once(|| conn.poll()).unwrap() {
+            Async::Ready(Some(stream)) => assert_eq!(stream::Id::new(1), stream.id),
+            _ => panic!("expected inbound stream")
+        }
+    }
+
+    #[test]
+    fn shutdown_signal_lets_open_streams_finish() {
+        let (a, b) = memory::pair();
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut conn = Connection::builder(a, Mode::Server).shutdown_signal(rx).build();
+        let remote = send(Framed::new(b, FrameCodec::default()), syn(1));
+        let mut stream = match poll_once(|| conn.poll()).unwrap() {
+            Async::Ready(Some(stream)) => stream,
+            _ => panic!("expected inbound stream")
+        };
+        tx.send(()).unwrap();
+        assert!(poll_once(|| conn.poll()).unwrap().is_not_ready());
+        assert!(conn.open_stream().unwrap().is_none());
+
+        let (frame, remote) = recv(remote);
+        assert_eq!(Type::GoAway, frame.header.typ);
+        let remote = send(remote, data(1, "request"));
+        let mut buf = [0; 7];
+        assert_eq!(7, poll_once(|| io::Read::read(&mut stream, &mut buf)).unwrap());
+        assert_eq!(b"request", &buf);
+        assert_eq!(5, poll_once(|| io::Write::write(&mut stream, b"reply")).unwrap());
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+        let (frame, remote) = recv(remote);
+        assert_eq!(Type::Data, frame.header.typ);
+        assert_eq!(&b"reply"[..], &frame.body[..]);
+
+        drop(stream);
+        assert!(poll_once(|| conn.poll()).unwrap().is_ready());
+        let (frame, remote) = recv(remote);
+        assert!(is_reset(&frame, 1));
+        assert!(remote.into_future().wait().ok().unwrap().0.is_none())
+    }
+
+    #[test]
+    fn drain_timeout_closes_connection_with_open_streams() {
+        let (a, b) = memory::pair();
+        let (tx, rx) = oneshot::channel::<()>();
+        let mut conn = Connection::builder(a, Mode::Server)
+            .shutdown_signal(rx)
+            .drain_timeout(Duration::from_secs(0))
+            .build();
+        let _remote = send(Framed::new(b, FrameCodec::default()), syn(1));
+        let stream = match poll_once(|| conn.poll()).unwrap() {
+            Async::Ready(Some(stream)) => stream,
+            _ => panic!("expected inbound stream")
+        };
+        tx.send(()).unwrap();
+        assert!(poll_once(|| conn.poll()).unwrap().is_ready());
+        assert!(conn.debug_dump().is_dead());
+        drop(stream)
+    }
+
+    #[cfg(feature = "frame-history")]
+    #[test]
+    fn frame_history_detects_data_before_syn() {
+        let (a, b) = memory::pair();
+        let mut cfg = Config::default();
+        cfg.set_frame_history(16);
+        let mut conn = Connection::new(a, cfg, Mode::Server);
+        let _remote = send(Framed::new(b, FrameCodec::default()), data(1, "hello"));
+        match poll_once(|| conn.poll()) {
+            Err(ConnectionError::FrameOrder(_)) => {}
+            other => panic!("unexpected result: {:?}", other.map(|a| a.map(|s| s.map(|s| s.id))))
+        }
+        assert!(conn.debug_dump().is_dead())
+    }
+
+    #[test]
+    fn congestion_defers_outbound_work() {
+        let (a, b) = memory::pair_with_capacity(1024);
+        let mut cfg = Config::default();
+        cfg.set_congestion_threshold(Some(Duration::from_secs(0)));
+        let conn = Connection::new(a, cfg, Mode::Client);
+        let mut remote = Framed::new(b, FrameCodec::default());
+        let mut events = conn.events();
+        let mut stream = conn.open_stream().unwrap().unwrap();
+
+        // Write until the transport is full and the connection congested.
+        let chunk = [0; 1024];
+        let mut written = 0;
+        while poll_once(|| io::Write::write(&mut stream, &chunk)).is_ok() {
+            written += 1;
+            assert!(written < 256, "connection never congested")
+        }
+        match poll_once(|| events.poll()) {
+            Ok(Async::Ready(Some(ConnectionEvent::Congested(_)))) => {}
+            other => panic!("unexpected event: {:?}", other)
+        }
+        match conn.open_stream() {
+            Err(ConnectionError::Congested) => {}
+            other => panic!("unexpected result: {:?}", other.map(|s| s.is_some()))
+        }
+
+        // Once the remote reads everything, outbound work resumes.
+        let mut received = 0;
+        loop {
+            let is_flushed = poll_once(|| conn.flush()).unwrap().is_ready();
+            while let Async::Ready(Some(frame)) = poll_once(|| remote.poll()).unwrap() {
+                received += frame.body.len()
+            }
+            if is_flushed {
+                break
+            }
+        }
+        assert_eq!(written * chunk.len(), received);
+        match poll_once(|| events.poll()) {
+            Ok(Async::Ready(Some(ConnectionEvent::Decongested))) => {}
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert!(poll_once(|| io::Write::write(&mut stream, &chunk)).is_ok())
+    }
+
+    #[test]
+    fn window_updates_overtake_blocked_data() {
+        let (a, b) = memory::pair_with_capacity(1024);
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        let mut remote = Framed::new(b, FrameCodec::default());
+        let mut stream = conn.open_stream().unwrap().unwrap();
+        let chunk = [0; 1024];
+        for _ in 0 .. 32 {
+            poll_once(|| io::Write::write(&mut stream, &chunk)).unwrap();
+        }
+        assert!(conn.debug_dump().pending().len() > 1);
+
+        // Credit for the remote is queued behind data frames which can not be sent.
+        let frame = Frame::window_update(stream.id, 1).into_raw();
+        conn.inner.lock().apply(vec![Action::Send(frame, Instant::now())]);
+        let pending = conn.debug_dump().pending().to_vec();
+        assert_eq!(Type::WindowUpdate, pending[0].frame.header.typ);
+        let queued = pending.len() - 1;
+
+        let mut frames = Vec::new();
+        loop {
+            let is_flushed = poll_once(|| conn.flush()).unwrap().is_ready();
+            while let Async::Ready(Some(frame)) = poll_once(|| remote.poll()).unwrap() {
+                frames.push(frame.header)
+            }
+            if is_flushed {
+                break
+            }
+        }
+        let after = frames.iter()
+            .skip_while(|h| h.typ != Type::WindowUpdate || h.flags.contains(SYN))
+            .skip(1)
+            .collect::<Vec<_>>();
+        assert_eq!(queued, after.len());
+        assert!(after.iter().all(|h| h.typ == Type::Data))
+    }
+
+    #[test]
+    fn window_updates_follow_the_syn_of_their_stream() {
+        let (a, b) = memory::pair();
+        let mut cfg = Config::default();
+        cfg.set_open_frame(OpenFrame::Data);
+        cfg.set_receive_window(4 * DEFAULT_CREDIT).unwrap();
+        let conn = Connection::new(a, cfg, Mode::Client);
+        let mut remote = Framed::new(b, FrameCodec::default());
+        let _stream = conn.open_stream().unwrap().unwrap();
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+        let mut frames = Vec::new();
+        while let Async::Ready(Some(frame)) = poll_once(|| remote.poll()).unwrap() {
+            frames.push(frame.header)
+        }
+        assert_eq!(2, frames.len());
+        assert_eq!(Type::Data, frames[0].typ);
+        assert!(frames[0].flags.contains(SYN));
+        assert_eq!(Type::WindowUpdate, frames[1].typ);
+        assert_eq!(3 * DEFAULT_CREDIT, frames[1].length.0)
+    }
+
+    #[test]
+    fn buffers_come_from_provider() {
+        #[derive(Default)]
+        struct Counting(AtomicUsize);
+
+        impl BufferProvider for Counting {
+            fn alloc(&self, capacity: usize) -> BytesMut {
+                self.0.fetch_add(capacity, Ordering::SeqCst);
+                BytesMut::with_capacity(capacity)
+            }
+        }
+
+        let (a, _b) = memory::pair();
+        let buffers = Arc::new(Counting::default());
+        let conn = Connection::builder(a, Mode::Client).buffer_provider(buffers.clone()).build();
+        assert_eq!(2 * CODEC_BUFFER_SIZE, buffers.0.load(Ordering::SeqCst));
+        let mut stream = conn.open_stream().unwrap().unwrap();
+        poll_once(|| io::Write::write(&mut stream, b"hello")).unwrap();
+        assert_eq!(2 * CODEC_BUFFER_SIZE + 5, buffers.0.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn ids_come_from_allocator() {
+        struct Stride(u32);
+
+        impl IdAllocator for Stride {
+            fn next_id(&mut self) -> Option<u32> {
+                self.0 += 10;
+                Some(self.0)
+            }
+        }
+
+        struct Countdown(u32);
+
+        impl IdAllocator for Countdown {
+            fn next_id(&mut self) -> Option<u32> {
+                self.0 = self.0.checked_sub(2)?;
+                Some(self.0)
+            }
+        }
+
+        let (a, _b) = memory::pair();
+        let conn = Connection::builder(a, Mode::Client).id_allocator(Box::new(Stride(1))).build();
+        let streams = (0 .. 3).map(|_| conn.open_stream().unwrap().unwrap()).collect::<Vec<_>>();
+        let ids = streams.iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(vec![stream::Id::new(11), stream::Id::new(21), stream::Id::new(31)], ids);
+        poll_once(|| conn.flush()).unwrap();
+
+        let (a, _b) = memory::pair();
+        let conn = Connection::builder(a, Mode::Client).id_allocator(Box::new(Countdown(7))).build();
+        let stream = conn.open_stream().unwrap().unwrap();
+        assert_eq!(stream::Id::new(5), stream.id);
+        match conn.open_stream() {
+            Err(ConnectionError::InvalidStreamId(id)) => assert_eq!(stream::Id::new(3), id),
+            other => panic!("unexpected result: {:?}", other.map(|s| s.map(|s| s.id)))
+        }
+    }
+
+    #[test]
+    fn handles_can_be_dropped_after_transport_failure() {
+        let (a, b) = memory::pair_with_capacity(64);
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        let mut s1 = conn.open_stream().unwrap().unwrap();
+        let chunk = vec![0; 100 * 1024];
+        for _ in 0 .. 2 {
+            poll_once(|| io::Write::write(&mut s1, &chunk)).unwrap();
+        }
+        let s2 = conn.open_stream().unwrap().unwrap();
+        assert_eq!(1, conn.pending_opens());
+        drop(b);
+        assert!(poll_once(|| conn.flush()).is_err());
+        drop(s2);
+        drop(s1);
+        assert_eq!(0, conn.pending_opens())
+    }
+
+    #[test]
+    fn pending_opens_are_capped() {
+        let (a, _b) = memory::pair();
+        let mut cfg = Config::default();
+        cfg.set_max_pending_opens(Some(2));
+        let conn = Connection::new(a, cfg, Mode::Client);
+        let s1 = conn.open_stream().unwrap().unwrap();
+        let _s2 = conn.open_stream().unwrap().unwrap();
+        assert_eq!(2, conn.pending_opens());
+        match conn.open_stream() {
+            Err(ConnectionError::TooManyPendingOpens) => {}
+            other => panic!("unexpected result: {:?}", other.map(|s| s.is_some()))
+        }
+
+        // Streams reset before being announced no longer count.
+        drop(s1);
+        assert_eq!(1, conn.pending_opens());
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+        assert_eq!(0, conn.pending_opens());
+        assert!(conn.open_stream().unwrap().is_some())
+    }
+
+    #[test]
+    fn transport_writes_are_counted() {
+        let (a, _b) = memory::pair();
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        assert_eq!(None, conn.stats().frames_per_write());
+
+        // The SYN and three data frames.
+        let mut stream = conn.open_stream().unwrap().unwrap();
+        for data in &[b"a", b"b", b"c"] {
+            poll_once(|| io::Write::write(&mut stream, *data)).unwrap();
+        }
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+        let stats = conn.stats();
+        let writes = stats.transport_writes();
+        assert!((1 ..= 4).contains(&writes));
+        assert_eq!(4 * HEADER_SIZE as u64 + 3, stats.transport_bytes_written());
+        assert_eq!(Some(4.0 / writes as f64), stats.frames_per_write());
+        assert_eq!(Some((4 * HEADER_SIZE + 3) as f64 / writes as f64), stats.bytes_per_write());
+        let json = conn.debug_dump().to_string();
+        assert!(json.contains(&format!("\"transport_writes\":{},", writes)))
+    }
+
+    #[test]
+    fn queue_latency_includes_time_before_flush() {
+        let (a, _b) = memory::pair();
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        assert_eq!(None, conn.stats().queue_latency(50.0));
+        let mut stream = conn.open_stream().unwrap().unwrap();
+        poll_once(|| io::Write::write(&mut stream, b"hello")).unwrap();
+        let delay = Duration::from_millis(50);
+        std::thread::sleep(delay);
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+        let stats = conn.stats();
+        assert!(stats.queue_latency(100.0).unwrap() >= delay);
+        assert!(stats.queue_latency(50.0).unwrap() <= stats.queue_latency(100.0).unwrap())
+    }
+
+    #[test]
+    fn soft_limits_are_emitted_as_events() {
+        let (a, _b) = memory::pair();
+        let mut cfg = Config::default();
+        cfg.set_max_num_streams(5);
+        let conn = Connection::new(a, cfg, Mode::Client);
+        let mut events = conn.events();
+        let _streams = (0 .. 5).map(|_| conn.open_stream().unwrap().unwrap()).collect::<Vec<_>>();
+        match poll_once(|| events.poll()) {
+            Ok(Async::Ready(Some(ConnectionEvent::SoftLimit(SoftLimit::Streams { open: 5, max: 5 })))) => {}
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert!(poll_once(|| events.poll()).unwrap().is_not_ready())
+    }
+
+    #[test]
+    fn custom_events_are_published() {
+        #[derive(Debug, PartialEq)]
+        struct Negotiated(&'static str);
+
+        let (a, _b) = memory::pair();
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        let mut events = conn.events();
+        let event = CustomEvent::new("negotiated", Negotiated("/echo/1.0.0"));
+        conn.publish(event.clone());
+        match poll_once(|| events.poll()) {
+            Ok(Async::Ready(Some(ConnectionEvent::Custom(e)))) => {
+                assert_eq!(event, e);
+                assert_eq!("negotiated", e.kind());
+                assert_eq!(Some(&Negotiated("/echo/1.0.0")), e.payload());
+                assert_eq!(None, e.payload::<String>())
+            }
+            other => panic!("unexpected event: {:?}", other)
+        }
+        assert_ne!(event, CustomEvent::new("negotiated", Negotiated("/echo/1.0.0")))
+    }
+
+    #[test]
+    fn go_aways_are_emitted_as_events() {
+        let (a, _b) = memory::pair();
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        let mut events = conn.events();
+        assert!(conn.shutdown().unwrap().is_ready());
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+        match poll_once(|| events.poll()) {
+            Ok(Async::Ready(Some(ConnectionEvent::GoAwaySent(reason)))) => {
+                assert_eq!(GoAwayReason::CLOSED, reason);
+                assert_eq!(header::CODE_TERM, reason.code())
+            }
+            other => panic!("unexpected event: {:?}", other)
+        }
+        conn.shutdown().unwrap();
+        assert!(poll_once(|| conn.flush()).unwrap().is_ready());
+        assert!(poll_once(|| events.poll()).unwrap().is_not_ready())
+    }
+
+    #[test]
+    fn slow_subscribers_miss_events() {
+        let (a, _b) = memory::pair();
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        let mut events = conn.events();
+        for i in 0 .. 2 * MAX_BUFFERED_EVENTS {
+            conn.publish(CustomEvent::new("test", i))
+        }
+        let mut received = Vec::new();
+        while let Async::Ready(Some(ConnectionEvent::Custom(e))) = poll_once(|| events.poll()).unwrap() {
+            received.push(*e.payload::<usize>().unwrap())
+        }
+        assert!(received.len() > MAX_BUFFERED_EVENTS && received.len() < 2 * MAX_BUFFERED_EVENTS);
+        assert_eq!((0 .. received.len()).collect::<Vec<_>>(), received);
+
+        conn.publish(CustomEvent::new("test", 0usize));
+        match poll_once(|| events.poll()) {
+            Ok(Async::Ready(Some(ConnectionEvent::Custom(e)))) => assert_eq!(Some(&0), e.payload::<usize>()),
+            other => panic!("unexpected event: {:?}", other)
+        }
+    }
+
+    #[cfg(feature = "body-age")]
+    #[test]
+    fn body_ages_show_where_data_waits() {
+        let (a, _b) = memory::pair_with_capacity(16);
+        let conn = Connection::new(a, Config::default(), Mode::Client);
+        let mut stream = conn.open_stream().unwrap().unwrap();
+        assert!(conn.debug_dump().body_ages().stream_queue().is_none());
+
+        poll_once(|| io::Write::write(&mut stream, b"hello")).unwrap();
+        let ages = conn.debug_dump().body_ages();
+        assert!(ages.stream_queue().is_some());
+        assert!(ages.codec_buffer().is_none());
+
+        // The transport only takes part of the frame.
+        assert!(poll_once(|| conn.flush()).unwrap().is_not_ready());
+        let ages = conn.debug_dump().body_ages();
+        assert!(ages.stream_queue().is_none());
+        assert!(ages.connection_queue().is_none());
+        assert!(ages.codec_buffer().is_some());
+        assert!(conn.debug_dump().to_string().contains("\"body_ages_ms\":{\"stream_queue\":null"))
+    }
+
+    #[test]
+    fn closing_sends_a_single_go_away() {
+        let (conn, remote) = server();
+        conn.shutdown().unwrap();
+        conn.shutdown().unwrap();
+        assert!(poll_once(|| conn.close()).unwrap().is_ready());
+        assert!(conn.close().unwrap().is_ready());
+        assert!(conn.debug_dump().is_dead());
+        assert!(conn.open_stream().unwrap().is_none());
+
+        let (frame, remote) = recv(remote);
+        assert_eq!(Type::GoAway, frame.header.typ);
+        assert!(remote.into_future().wait().ok().unwrap().0.is_none())
+    }
+}
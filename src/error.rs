@@ -9,6 +9,7 @@
 // at https://opensource.org/licenses/MIT.
 
 use crate::stream;
+use futures::future::ExecuteErrorKind;
 use quick_error::quick_error;
 use std::io;
 
@@ -26,6 +27,9 @@ quick_error! {
         FrameTooLarge(n: usize) {
             display("frame body is too large ({})", n)
         }
+        Truncated {
+            display("input ends with an incomplete frame")
+        }
         #[doc(hidden)]
         __Nonexhaustive
     }
@@ -53,9 +57,27 @@ quick_error! {
         StreamNotFound(id: stream::Id) {
             display("stream {} not found", id)
         }
+        InvalidStreamId(id: stream::Id) {
+            display("stream id {} can not be used", id)
+        }
         TooManyStreams {
             display("maximum number of streams exhausted")
         }
+        TooManyPings {
+            display("too many pings are awaiting a reply")
+        }
+        TooManyPendingOpens {
+            display("too many opened streams have not been sent yet")
+        }
+        Congested {
+            display("connection is congested")
+        }
+        Spawn(k: ExecuteErrorKind) {
+            display("failed to spawn stream handler: {:?}", k)
+        }
+        FrameOrder(e: String) {
+            display("received frames violate ordering invariants: {}", e)
+        }
         #[doc(hidden)]
         __Nonexhaustive
     }
@@ -0,0 +1,123 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::frame::GoAwayReason;
+use futures::sync::mpsc;
+use log::debug;
+use std::{any::Any, fmt, mem, sync::Arc, time::Duration};
+
+/// Something noteworthy which happened to a connection.
+///
+/// See `Connection::events`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Pending frames could not be written for the given time. New outbound
+    /// work is refused until the transport accepts frames again.
+    Congested(Duration),
+    /// The transport accepts frames again.
+    Decongested,
+    /// A configured limit is about to be reached.
+    SoftLimit(SoftLimit),
+    /// A GoAway has been sent to the remote for the given reason.
+    GoAwaySent(GoAwayReason),
+    /// An event of code layered on top of the connection, see
+    /// `Connection::publish`.
+    Custom(CustomEvent)
+}
+
+/// A limit a connection is getting close to.
+///
+/// Each is reported once when crossed and again only after the connection
+/// has dropped below it in the meantime, e.g. because streams have been
+/// closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SoftLimit {
+    /// More than 80% of `Config::set_max_num_streams` streams are open.
+    Streams { open: usize, max: usize },
+    /// A stream buffers more than 80% of `Config::set_max_buffer_size` bytes.
+    Buffer { stream: u32, buffered: usize, max: usize },
+    /// The stream IDs of this endpoint have passed 90% of the ID space.
+    StreamIds { next: u32 }
+}
+
+/// An event published by extension code, e.g. an interceptor or a
+/// negotiated sub-protocol.
+///
+/// The kind tells subscribers which payload type to expect. Two events are
+/// equal if they are of the same kind and share the same payload, i.e. are
+/// clones of each other.
+#[derive(Clone)]
+pub struct CustomEvent {
+    kind: &'static str,
+    payload: Arc<dyn Any + Send + Sync>
+}
+
+impl CustomEvent {
+    pub fn new<P: Any + Send + Sync>(kind: &'static str, payload: P) -> Self {
+        CustomEvent { kind, payload: Arc::new(payload) }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    /// The payload if it is of type `P`.
+    pub fn payload<P: Any>(&self) -> Option<&P> {
+        self.payload.downcast_ref()
+    }
+}
+
+impl fmt::Debug for CustomEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CustomEvent").field("kind", &self.kind).finish()
+    }
+}
+
+impl PartialEq for CustomEvent {
+    fn eq(&self, other: &CustomEvent) -> bool {
+        self.kind == other.kind && Arc::ptr_eq(&self.payload, &other.payload)
+    }
+}
+
+impl Eq for CustomEvent {}
+
+/// Max. number of events buffered for a subscriber which does not keep up.
+pub(crate) const MAX_BUFFERED_EVENTS: usize = 64;
+
+/// The subscribers to a connection's events.
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    senders: Vec<mpsc::Sender<ConnectionEvent>>
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&mut self) -> mpsc::Receiver<ConnectionEvent> {
+        let (tx, rx) = mpsc::channel(MAX_BUFFERED_EVENTS);
+        self.senders.push(tx);
+        rx
+    }
+
+    /// Send the event to every subscriber, forgetting those who have gone.
+    ///
+    /// Subscribers whose buffer is full miss the event.
+    pub(crate) fn emit(&mut self, event: ConnectionEvent) {
+        let senders = mem::replace(&mut self.senders, Vec::new());
+        self.senders = senders.into_iter()
+            .filter_map(|mut tx| match tx.try_send(event.clone()) {
+                Ok(()) => Some(tx),
+                Err(ref e) if e.is_full() => {
+                    debug!("subscriber does not keep up, dropping event {:?}", event);
+                    Some(tx)
+                }
+                Err(_) => None
+            })
+            .collect()
+    }
+}
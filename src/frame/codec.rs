@@ -11,6 +11,7 @@
 use bytes::{BigEndian, BufMut, ByteOrder, Bytes, BytesMut};
 use crate::{
     Config,
+    HEADER_SIZE,
     error::DecodeError,
     frame::{header::{Flags, Len, RawHeader, Type, Version}, RawFrame},
     stream
@@ -39,6 +40,11 @@ impl FrameCodec {
     pub fn default() -> FrameCodec {
         FrameCodec::new(&Config::default())
     }
+
+    /// Has a frame header been decoded whose body is still missing?
+    pub(crate) fn has_partial_frame(&self) -> bool {
+        self.header.is_some()
+    }
 }
 
 impl Encoder for FrameCodec {
@@ -100,7 +106,7 @@ impl Encoder for HeaderCodec {
     type Error = io::Error;
 
     fn encode(&mut self, hdr: Self::Item, bytes: &mut BytesMut) -> Result<(), Self::Error> {
-        bytes.reserve(12);
+        bytes.reserve(HEADER_SIZE);
         bytes.put_u8(hdr.version.0);
         bytes.put_u8(hdr.typ as u8);
         bytes.put_u16_be(hdr.flags.0);
@@ -115,10 +121,10 @@ impl Decoder for HeaderCodec {
     type Error = DecodeError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 12 {
+        if src.len() < HEADER_SIZE {
             return Ok(None)
         }
-        let src = src.split_to(12);
+        let src = src.split_to(HEADER_SIZE);
         let header = RawHeader {
             version: Version(src[0]),
             typ: match src[1] {
@@ -168,7 +174,7 @@ mod tests {
     #[test]
     fn frame_identity() {
         fn property(f: RawFrame) -> bool {
-            let mut buf = BytesMut::with_capacity(12 + f.body.len());
+            let mut buf = BytesMut::with_capacity(HEADER_SIZE + f.body.len());
             let mut codec = FrameCodec::default();
             if codec.encode(f.clone(), &mut buf).is_err() {
                 return false
@@ -11,6 +11,7 @@
 use crate::{frame::{Data, WindowUpdate, Ping, GoAway}, stream};
 use std::marker::PhantomData;
 
+/// The type of a frame.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Type {
     Data,
@@ -49,6 +50,9 @@ pub const SYN: Flags = Flags(1);
 pub const ACK: Flags = Flags(2);
 pub const FIN: Flags = Flags(4);
 pub const RST: Flags = Flags(8);
+/// Extension flag negotiating streams without flow control (not part of the
+/// yamux specification, see `Config::set_unlimited_streams`).
+pub const UNLIMITED: Flags = Flags(16);
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RawHeader {
@@ -116,6 +120,10 @@ impl Header<Data> {
         self.raw_header.flags.0 |= RST.0
     }
 
+    pub fn unlimited(&mut self) {
+        self.raw_header.flags.0 |= UNLIMITED.0
+    }
+
     pub fn len(&self) -> u32 {
         self.raw_header.length.0
     }
@@ -151,6 +159,10 @@ impl Header<WindowUpdate> {
         self.raw_header.flags.0 |= RST.0
     }
 
+    pub fn unlimited(&mut self) {
+        self.raw_header.flags.0 |= UNLIMITED.0
+    }
+
     pub fn credit(&self) -> u32 {
         self.raw_header.length.0
     }
@@ -8,23 +8,89 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
-use bytes::Bytes;
-use crate::{frame::header::{Header, RawHeader}, stream};
-use std::u32;
+use bytes::{Bytes, BytesMut};
+use crate::{
+    Config,
+    error::DecodeError,
+    frame::{codec::FrameCodec, header::{Header, RawHeader, CODE_TERM, ECODE_INTERNAL, ECODE_PROTO}},
+    stream
+};
+use std::{fmt, iter, u32};
+use tokio_codec::Decoder;
 
 pub mod codec;
 pub mod header;
 
+/// A decoded frame (see `iter_frames`).
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct RawFrame {
-    pub header: RawHeader,
-    pub body: Bytes
+    pub(crate) header: RawHeader,
+    pub(crate) body: Bytes
 }
 
 impl RawFrame {
     pub fn dyn_type(&self) -> header::Type {
         self.header.typ
     }
+
+    /// The flags of the frame header.
+    pub fn flags(&self) -> u16 {
+        self.header.flags.0
+    }
+
+    pub fn stream_id(&self) -> u32 {
+        self.header.stream_id.as_u32()
+    }
+
+    /// The length field of the frame header.
+    ///
+    /// Window updates carry the credit and pings the nonce here.
+    pub fn length(&self) -> u32 {
+        self.header.length.0
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+/// Decode all frames contained in `bytes`, e.g. captured yamux traffic.
+///
+/// Unlike a connection, this does not limit the size of frame bodies. Decoding
+/// stops at the first error. If `bytes` ends with an incomplete frame, the last
+/// item is `DecodeError::Truncated`.
+pub fn iter_frames(bytes: &[u8]) -> impl Iterator<Item=Result<RawFrame, DecodeError>> {
+    // No body can be larger than the input. The limit keeps the codec from
+    // reserving space for up to 4 GiB announced by a corrupted header.
+    let mut cfg = Config::default();
+    cfg.set_max_buffer_size(bytes.len());
+    let mut codec = FrameCodec::new(&cfg);
+    let mut buf = BytesMut::from(bytes);
+    let mut done = false;
+    iter::from_fn(move || {
+        if done {
+            return None
+        }
+        match codec.decode(&mut buf) {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                done = true;
+                if buf.is_empty() && !codec.has_partial_frame() {
+                    None
+                } else {
+                    Some(Err(DecodeError::Truncated))
+                }
+            }
+            Err(DecodeError::FrameTooLarge(_)) => {
+                done = true;
+                Some(Err(DecodeError::Truncated))
+            }
+            Err(e) => {
+                done = true;
+                Some(Err(e))
+            }
+        }
+    })
 }
 
 #[derive(Debug)]
@@ -99,5 +165,119 @@ impl Frame<GoAway> {
             body: Bytes::new()
         }
     }
+
+    /// A GoAway frame with the error code of `reason`.
+    pub fn go_away_for(reason: GoAwayReason) -> Self {
+        Frame::go_away(reason.code)
+    }
+}
+
+/// Why a GoAway frame is sent: an error code and a description of it.
+///
+/// Only the code goes over the wire. The description is logged and reported
+/// by `ConnectionEvent::GoAwaySent`, so that the GoAway can be matched with
+/// the check which failed when reading the logs of both endpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GoAwayReason {
+    code: u32,
+    description: &'static str
+}
+
+impl GoAwayReason {
+    /// The connection is closed locally.
+    pub const CLOSED: GoAwayReason = GoAwayReason { code: CODE_TERM, description: "connection closed" };
+    /// A data frame's length field differs from its body.
+    pub const LENGTH_MISMATCH: GoAwayReason =
+        GoAwayReason { code: ECODE_PROTO, description: "frame length does not match body" };
+    /// A stream ID of the wrong parity has been opened.
+    pub const INVALID_STREAM_ID: GoAwayReason = GoAwayReason { code: ECODE_PROTO, description: "invalid stream id" };
+    /// A stream has been opened twice.
+    pub const DUPLICATE_STREAM: GoAwayReason = GoAwayReason { code: ECODE_PROTO, description: "stream already exists" };
+    /// A stream has been opened with more data than the default credit.
+    pub const INITIAL_DATA_TOO_LARGE: GoAwayReason =
+        GoAwayReason { code: ECODE_PROTO, description: "initial data exceeds default credit" };
+    /// A data frame exceeds the receive window of its stream.
+    pub const WINDOW_EXCEEDED: GoAwayReason =
+        GoAwayReason { code: ECODE_PROTO, description: "frame body larger than window" };
+    /// A window update makes the credit of a stream overflow.
+    pub const CREDIT_OVERFLOW: GoAwayReason =
+        GoAwayReason { code: ECODE_PROTO, description: "credit exceeds max. window size" };
+    /// The remote has opened more streams than allowed.
+    pub const TOO_MANY_STREAMS: GoAwayReason =
+        GoAwayReason { code: ECODE_INTERNAL, description: "maximum number of streams reached" };
+    /// The local application has reset too many inbound streams.
+    pub const TOO_MANY_RESETS: GoAwayReason =
+        GoAwayReason { code: ECODE_INTERNAL, description: "too many inbound streams reset" };
+    /// The remote opens streams faster than allowed.
+    pub const INBOUND_RATE_EXCEEDED: GoAwayReason =
+        GoAwayReason { code: ECODE_INTERNAL, description: "remote opens streams too fast" };
+
+    /// A reason for other checks, e.g. of a custom frame source. Any code is
+    /// accepted.
+    pub fn new(code: u32, description: &'static str) -> Self {
+        GoAwayReason { code, description }
+    }
+
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.description
+    }
 }
 
+impl fmt::Display for GoAwayReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (code {})", self.description, self.code)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::{error::DecodeError, frame::codec::FrameCodec};
+    use tokio_codec::Encoder;
+    use super::*;
+
+    fn encode(frames: &[RawFrame]) -> BytesMut {
+        let mut codec = FrameCodec::default();
+        let mut buf = BytesMut::new();
+        for f in frames {
+            codec.encode(f.clone(), &mut buf).unwrap()
+        }
+        buf
+    }
+
+    #[test]
+    fn iter_frames_decodes_captured_bytes() {
+        let frames = vec![
+            Frame::window_update(stream::Id::new(1), 1024).into_raw(),
+            Frame::data(stream::Id::new(1), Bytes::from("hello")).into_raw(),
+            Frame::go_away(0).into_raw()
+        ];
+        let buf = encode(&frames);
+        let decoded = iter_frames(&buf).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(frames, decoded);
+
+        for n in 1 .. 5 {
+            let mut items = iter_frames(&buf[.. buf.len() - n]).collect::<Vec<_>>();
+            match items.pop() {
+                Some(Err(DecodeError::Truncated)) => {}
+                other => panic!("unexpected last item: {:?}", other)
+            }
+            assert_eq!(2, items.len())
+        }
+    }
+
+    #[test]
+    fn iter_frames_does_not_trust_max_lengths() {
+        let mut frame = Frame::data(stream::Id::new(1), Bytes::from("hello")).into_raw();
+        frame.header.length = header::Len(u32::MAX);
+        match iter_frames(&encode(&[frame])).collect::<Vec<_>>().as_slice() {
+            [Err(DecodeError::Truncated)] => {}
+            other => panic!("unexpected items: {:?}", other)
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! A debugging aid which records the most recent frame headers of a
+//! connection and checks them against basic ordering invariants.
+//!
+//! Only available with the `frame-history` feature and enabled per
+//! connection by `Config::set_frame_history`.
+
+use crate::frame::header::{FIN, RST, SYN, RawHeader, Type};
+use std::{collections::{HashSet, VecDeque}, fmt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Direction { Sent, Received }
+
+pub(crate) struct History {
+    capacity: usize,
+    headers: VecDeque<(Direction, RawHeader)>,
+    /// The highest stream ID opened with a SYN, for even and odd IDs.
+    max_opened: [u32; 2],
+    /// Streams which have been opened and not been closed in both directions.
+    open: HashSet<u32>,
+    /// Open streams which have been half-closed in the given direction.
+    finished: HashSet<(Direction, u32)>
+}
+
+impl History {
+    pub(crate) fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            headers: VecDeque::with_capacity(capacity),
+            max_opened: [0, 0],
+            open: HashSet::new(),
+            finished: HashSet::new()
+        }
+    }
+
+    /// Record a header and check it against the ones seen before.
+    ///
+    /// Returns a description of the violated invariant, if any. Streams must
+    /// be opened with a SYN before any other frame of theirs, must not be
+    /// opened while they are open and must not carry data in a direction
+    /// after a FIN. Streams are forgotten once they are closed in both
+    /// directions or reset.
+    pub(crate) fn record(&mut self, dir: Direction, header: &RawHeader) -> Result<(), String> {
+        if self.headers.len() == self.capacity {
+            self.headers.pop_front();
+        }
+        if self.capacity > 0 {
+            self.headers.push_back((dir, header.clone()))
+        }
+
+        let id = header.stream_id.as_u32();
+        if id == 0 || header.typ == Type::Ping || header.typ == Type::GoAway {
+            return Ok(())
+        }
+        if header.flags.contains(RST) {
+            self.forget(id);
+            return Ok(())
+        }
+
+        let max_opened = &mut self.max_opened[(id % 2) as usize];
+        if header.flags.contains(SYN) {
+            if !self.open.insert(id) {
+                return Err(format!("stream {} opened again while open", id))
+            }
+            *max_opened = std::cmp::max(*max_opened, id)
+        } else if !self.open.contains(&id) {
+            if id > *max_opened {
+                return Err(format!("{:?} frame for stream {} before its SYN", header.typ, id))
+            }
+            // A late frame for a stream which has been closed already.
+            return Ok(())
+        }
+
+        if header.typ == Type::Data && header.length.0 > 0 && self.finished.contains(&(dir, id)) {
+            return Err(format!("data for stream {} after its FIN", id))
+        }
+        if header.flags.contains(FIN) {
+            self.finished.insert((dir, id));
+            if self.finished.contains(&(Direction::Sent, id)) && self.finished.contains(&(Direction::Received, id)) {
+                self.forget(id)
+            }
+        }
+        Ok(())
+    }
+
+    fn forget(&mut self, id: u32) {
+        self.open.remove(&id);
+        self.finished.remove(&(Direction::Sent, id));
+        self.finished.remove(&(Direction::Received, id));
+    }
+}
+
+impl fmt::Display for History {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "last {} frame headers:", self.headers.len())?;
+        for (dir, h) in &self.headers {
+            writeln!(f, "  {:?}: {:?} stream={} flags={} length={}", dir, h.typ, h.stream_id, h.flags.0, h.length.0)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{frame::header::{Flags, Len, Version}, stream};
+    use super::*;
+
+    fn header(typ: Type, id: u32, flags: Flags, len: u32) -> RawHeader {
+        RawHeader { version: Version(0), typ, flags, stream_id: stream::Id::new(id), length: Len(len) }
+    }
+
+    #[test]
+    fn only_the_last_headers_are_kept() {
+        let mut history = History::new(2);
+        for &id in &[1, 3, 5] {
+            history.record(Direction::Sent, &header(Type::WindowUpdate, id, SYN, 0)).unwrap()
+        }
+        let ids = history.headers.iter().map(|(_, h)| h.stream_id.as_u32()).collect::<Vec<_>>();
+        assert_eq!(vec![3, 5], ids);
+        assert_eq!(3, history.to_string().lines().count())
+    }
+
+    #[test]
+    fn frames_before_syn_are_a_violation() {
+        let mut history = History::new(8);
+        assert!(history.record(Direction::Received, &header(Type::Data, 2, Flags(0), 4)).is_err());
+        history.record(Direction::Received, &header(Type::Data, 2, SYN, 4)).unwrap();
+        history.record(Direction::Sent, &header(Type::WindowUpdate, 2, Flags(0), 4)).unwrap();
+        assert!(history.record(Direction::Received, &header(Type::Data, 2, SYN, 4)).is_err())
+    }
+
+    #[test]
+    fn data_after_fin_is_a_violation() {
+        let mut history = History::new(8);
+        history.record(Direction::Sent, &header(Type::Data, 1, SYN, 4)).unwrap();
+        history.record(Direction::Sent, &header(Type::Data, 1, FIN, 0)).unwrap();
+        history.record(Direction::Received, &header(Type::Data, 1, Flags(0), 4)).unwrap();
+        history.record(Direction::Sent, &header(Type::WindowUpdate, 1, Flags(0), 4)).unwrap();
+        assert!(history.record(Direction::Sent, &header(Type::Data, 1, Flags(0), 4)).is_err());
+        history.record(Direction::Received, &header(Type::Data, 1, RST, 0)).unwrap();
+        assert!(history.open.is_empty());
+        assert!(history.finished.is_empty())
+    }
+
+    #[test]
+    fn streams_closed_in_both_directions_are_forgotten() {
+        let mut history = History::new(8);
+        for &id in &[3, 1] {
+            history.record(Direction::Sent, &header(Type::Data, id, SYN, 4)).unwrap();
+            history.record(Direction::Sent, &header(Type::Data, id, FIN, 0)).unwrap();
+            history.record(Direction::Received, &header(Type::Data, id, FIN, 0)).unwrap();
+        }
+        assert!(history.open.is_empty());
+        assert!(history.finished.is_empty());
+        history.record(Direction::Received, &header(Type::WindowUpdate, 1, Flags(0), 4)).unwrap();
+        history.record(Direction::Sent, &header(Type::Data, 1, SYN, 4)).unwrap()
+    }
+}
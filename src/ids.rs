@@ -0,0 +1,54 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{connection::Mode, FIRST_CLIENT_STREAM_ID, FIRST_SERVER_STREAM_ID};
+
+/// Chooses the IDs of the streams a connection opens.
+///
+/// Useful for deterministic IDs in simulations or for IDs which are derived
+/// from IDs of another layer. The connection still checks every ID it is
+/// given: it must not be 0, must be odd for clients and even for servers and
+/// must be greater than every ID handed out before. Otherwise opening the
+/// stream fails with `ConnectionError::InvalidStreamId`.
+///
+/// If the most recently opened stream is abandoned before the remote learned
+/// about it, its ID is used again for the next stream without asking the
+/// allocator.
+///
+/// See `Builder::id_allocator`.
+pub trait IdAllocator: Send {
+    /// The ID of the next stream or `None` if there are no more.
+    fn next_id(&mut self) -> Option<u32>;
+}
+
+/// Hands out increasing IDs, starting with the first one of the given mode.
+/// This is the default.
+#[derive(Clone, Debug)]
+pub struct SequentialIds {
+    next: Option<u32>
+}
+
+impl SequentialIds {
+    pub fn new(mode: Mode) -> Self {
+        let first = match mode {
+            Mode::Client => FIRST_CLIENT_STREAM_ID,
+            Mode::Server => FIRST_SERVER_STREAM_ID
+        };
+        SequentialIds { next: Some(first) }
+    }
+}
+
+impl IdAllocator for SequentialIds {
+    fn next_id(&mut self) -> Option<u32> {
+        let id = self.next?;
+        self.next = id.checked_add(2);
+        Some(id)
+    }
+}
@@ -25,20 +25,81 @@ extern crate parking_lot;
 #[cfg(test)]
 extern crate quickcheck;
 extern crate quick_error;
+extern crate rand;
 extern crate tokio_io;
 extern crate tokio_codec;
+#[cfg(feature = "tokio-tcp")]
+extern crate tokio_tcp;
+#[cfg(all(unix, feature = "tokio-uds"))]
+extern crate tokio_uds;
 
+mod buffer;
+mod clock;
+#[cfg(test)]
+mod conformance;
 mod connection;
 mod error;
+mod event;
 #[allow(dead_code)]
 mod frame;
+#[cfg(feature = "frame-history")]
+mod history;
+mod ids;
+pub mod memory;
+#[cfg(any(feature = "tokio-tcp", all(unix, feature = "tokio-uds")))]
+mod net;
 mod notify;
+mod rate;
+mod session;
+mod snapshot;
+mod stats;
 mod stream;
 
-pub use crate::connection::{Connection, Mode, StreamHandle};
+pub use crate::buffer::{BufferProvider, GlobalBuffers};
+pub use crate::connection::{
+    Builder,
+    Connection,
+    EndReason,
+    ExpectStream,
+    Inbound,
+    Incoming,
+    Matcher,
+    Mode,
+    RequestHandler,
+    StreamHandle
+};
 pub use crate::error::{DecodeError, ConnectionError};
+pub use crate::event::{ConnectionEvent, CustomEvent, SoftLimit};
+pub use crate::frame::{iter_frames, header::Type as FrameType, GoAwayReason, RawFrame};
+pub use crate::ids::{IdAllocator, SequentialIds};
+pub use crate::session::ConnectionState;
+pub use crate::snapshot::{FrameSnapshot, Snapshot, StreamSnapshot};
+#[cfg(feature = "body-age")]
+pub use crate::snapshot::BodyAges;
+pub use crate::stats::Stats;
+
+use std::time::Duration;
+
+/// Size of a frame header in bytes.
+pub const HEADER_SIZE: usize = 12;
+
+/// Initial receive window and send credit of every stream, as per yamux specification.
+pub const DEFAULT_CREDIT: u32 = 256 * 1024;
+
+/// Max. value of the length field of a frame header.
+///
+/// The frame bodies a connection accepts are further limited by its
+/// configuration (see `Config::effective_limits`).
+pub const MAX_FRAME_LEN: u32 = u32::MAX;
+
+/// The first stream ID used by clients. Client stream IDs are odd.
+pub const FIRST_CLIENT_STREAM_ID: u32 = 1;
+
+/// The first stream ID used by servers. Server stream IDs are even.
+pub const FIRST_SERVER_STREAM_ID: u32 = 2;
 
-pub(crate) const DEFAULT_CREDIT: u32 = 256 * 1024; // as per yamux specification
+/// The largest stream ID. ID 0 refers to the connection itself.
+pub const MAX_STREAM_ID: u32 = u32::MAX;
 
 /// Specifies when window update frames are sent.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -54,6 +115,10 @@ pub enum WindowUpdateMode {
 
     /// Send window updates only when data is read on the receiving end.
     ///
+    /// The remote is credited with the number of bytes actually read, once half
+    /// of the receive window has been consumed, so applications reading in small
+    /// chunks naturally pace the sender.
+    ///
     /// This ensures that senders do not overwhelm receivers and keeps buffer usage
     /// low. However, depending on the protocol, there is a risk of deadlock, namely
     /// if both endpoints want to send data larger than the receivers window and they
@@ -67,6 +132,46 @@ pub enum WindowUpdateMode {
     OnRead
 }
 
+/// What to do with inbound streams exceeding `Config::set_max_inbound_streams_per_sec`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InboundStreamPolicy {
+    /// Reset every excess stream.
+    Reset,
+    /// Send a GoAway frame and refuse all further streams.
+    GoAway
+}
+
+/// What to do with data frames whose header length differs from their body.
+///
+/// `FrameCodec` never produces such frames, but other transports or decoders
+/// handing frames to a connection might.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthMismatchPolicy {
+    /// Reset the stream the frame belongs to.
+    Reset,
+    /// Treat it as a protocol error and send a GoAway frame.
+    GoAway
+}
+
+/// The frame which opens a stream if no data is written yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenFrame {
+    /// A window update with the SYN flag which announces the receive window.
+    ///
+    /// The window update carries the whole receive window, as this crate has
+    /// always done. The yamux specification (and e.g. the Go implementation)
+    /// reads it as an increment over the default credit of 256 KiB instead,
+    /// so such remotes assume 256 KiB more credit than we have granted. Only
+    /// use this with remotes running older versions of this crate.
+    WindowUpdate,
+    /// A data frame with the SYN flag and an empty body (the default).
+    ///
+    /// The remote assumes the default credit of 256 KiB for such streams, so
+    /// a larger receive window is announced by a window update right after,
+    /// as an increment. Every implementation agrees on this encoding.
+    Data
+}
+
 /// Yamux configuration.
 ///
 /// The default configuration values are as follows:
@@ -75,12 +180,30 @@ pub enum WindowUpdateMode {
 /// - max. buffer size (per stream) = 1 MiB
 /// - max. number of streams = 8192
 /// - window update mode = on receive
+/// - max. number of stream resets per second = unlimited
+/// - max. number of inbound streams per second = unlimited
+/// - inbound stream policy = reset
+/// - length mismatch policy = go away
+/// - congestion threshold = none
+/// - unlimited streams = off
+/// - max. number of pending stream opens = unlimited
+/// - open frame = data
 #[derive(Debug, Clone)]
 pub struct Config {
     pub(crate) receive_window: u32,
     pub(crate) max_buffer_size: usize,
     pub(crate) max_num_streams: usize,
-    pub(crate) window_update_mode: WindowUpdateMode
+    pub(crate) window_update_mode: WindowUpdateMode,
+    pub(crate) max_resets_per_sec: Option<u32>,
+    pub(crate) max_inbound_streams_per_sec: Option<u32>,
+    pub(crate) inbound_stream_policy: InboundStreamPolicy,
+    pub(crate) length_mismatch_policy: LengthMismatchPolicy,
+    pub(crate) congestion_threshold: Option<Duration>,
+    pub(crate) unlimited_streams: bool,
+    pub(crate) max_pending_opens: Option<usize>,
+    pub(crate) open_frame: OpenFrame,
+    #[cfg(feature = "frame-history")]
+    pub(crate) frame_history: usize
 }
 
 impl Default for Config {
@@ -89,12 +212,57 @@ impl Default for Config {
             receive_window: DEFAULT_CREDIT,
             max_buffer_size: 1024 * 1024,
             max_num_streams: 8192,
-            window_update_mode: WindowUpdateMode::OnReceive
+            window_update_mode: WindowUpdateMode::OnReceive,
+            max_resets_per_sec: None,
+            max_inbound_streams_per_sec: None,
+            inbound_stream_policy: InboundStreamPolicy::Reset,
+            length_mismatch_policy: LengthMismatchPolicy::GoAway,
+            congestion_threshold: None,
+            unlimited_streams: false,
+            max_pending_opens: None,
+            open_frame: OpenFrame::Data,
+            #[cfg(feature = "frame-history")]
+            frame_history: 0
         }
     }
 }
 
 impl Config {
+    /// A configuration for running over a transport which is itself
+    /// multiplexed and flow-controlled, e.g. a QUIC stream or an SSH channel.
+    ///
+    /// With the defaults, every stream buffers up to 1 MiB regardless of
+    /// whether the application reads it, so data is drained from the outer
+    /// transport and its flow control never pushes back on the remote. This
+    /// preset instead
+    ///
+    /// - sends window updates only for data which has been read (see
+    ///   `WindowUpdateMode::OnRead` for the caveats),
+    /// - keeps the receive window at its minimum of 256 KiB, and
+    /// - limits stream buffers to the receive window.
+    ///
+    /// Thus a slow reader stalls its stream and, once all streams stall, the
+    /// outer transport. Connections never send pings on their own, so nothing
+    /// needs to be disabled to keep idle outer transports quiet.
+    pub fn for_nested_transport() -> Self {
+        Config {
+            receive_window: DEFAULT_CREDIT,
+            max_buffer_size: DEFAULT_CREDIT as usize,
+            window_update_mode: WindowUpdateMode::OnRead,
+            .. Config::default()
+        }
+    }
+
+    /// Get the limits resulting from this configuration.
+    pub fn effective_limits(&self) -> Limits {
+        Limits {
+            receive_window: self.receive_window,
+            max_frame_body: std::cmp::min(self.max_buffer_size, self.receive_window as usize),
+            max_buffer_size: self.max_buffer_size,
+            max_num_streams: self.max_num_streams
+        }
+    }
+
     /// Set the receive window (must be >= 256 KiB).
     pub fn set_receive_window(&mut self, n: u32) -> Result<(), ()> {
         if n >= DEFAULT_CREDIT {
@@ -118,5 +286,140 @@ impl Config {
     pub fn set_window_update_mode(&mut self, m: WindowUpdateMode) {
         self.window_update_mode = m
     }
+
+    /// Set the max. number of inbound streams we may reset per second.
+    ///
+    /// If the local application resets more inbound streams than this, e.g.
+    /// because it is overloaded, the connection sends a GoAway frame and
+    /// refuses all further streams the remote tries to open. These are reset
+    /// as well, but at a fixed, low rate; beyond it they are dropped silently.
+    /// Resets below the limit are sent one per stream and are not coalesced.
+    pub fn set_max_resets_per_sec(&mut self, n: Option<u32>) {
+        self.max_resets_per_sec = n
+    }
+
+    /// Set the max. number of streams the remote may open per second.
+    ///
+    /// Unlike `set_max_num_streams`, which limits how many streams may be open
+    /// at the same time, this limits how fast the remote may open them. What
+    /// happens to excess streams is determined by `set_inbound_stream_policy`.
+    pub fn set_max_inbound_streams_per_sec(&mut self, n: Option<u32>) {
+        self.max_inbound_streams_per_sec = n
+    }
+
+    /// Set what to do with inbound streams exceeding the max. rate.
+    pub fn set_inbound_stream_policy(&mut self, p: InboundStreamPolicy) {
+        self.inbound_stream_policy = p
+    }
+
+    /// Set what to do with data frames whose length field does not match
+    /// the length of their body.
+    pub fn set_length_mismatch_policy(&mut self, p: LengthMismatchPolicy) {
+        self.length_mismatch_policy = p
+    }
+
+    /// Set for how long pending frames may fail to be written before the
+    /// connection is considered congested.
+    ///
+    /// While congested, stream writes are deferred and no new streams may be
+    /// opened, so that frames the transport can not take do not pile up.
+    /// Inbound frames are still read. Congestion is checked whenever the
+    /// connection is polled and reported by `Connection::events`.
+    pub fn set_congestion_threshold(&mut self, d: Option<Duration>) {
+        self.congestion_threshold = d
+    }
+
+    /// Disable flow control for streams opened by either side.
+    ///
+    /// Meant for trusted links, e.g. local IPC, where window updates are pure
+    /// overhead. Streams we open carry an extension flag in their opening frame
+    /// requesting this, but otherwise announce the receive window as usual. A
+    /// remote which has this enabled as well acknowledges the request with a
+    /// flagged window update, after which neither side counts credit or sends
+    /// window updates for the stream. Until then, and if the remote has it
+    /// disabled or is unaware of the extension, the stream is flow controlled
+    /// like any other.
+    ///
+    /// Stream buffers are still limited by `set_max_buffer_size`, i.e. streams
+    /// whose reader falls too far behind are reset.
+    pub fn set_unlimited_streams(&mut self, enable: bool) {
+        self.unlimited_streams = enable
+    }
+
+    /// Set the max. number of outbound streams whose opening frame has not
+    /// been sent yet.
+    ///
+    /// Beyond this, `Connection::open_stream` fails with
+    /// `ConnectionError::TooManyPendingOpens` until the connection has been
+    /// flushed, instead of queueing ever more frames. See also
+    /// `Connection::pending_opens`.
+    pub fn set_max_pending_opens(&mut self, n: Option<usize>) {
+        self.max_pending_opens = n
+    }
+
+    /// Set the frame which opens outbound streams.
+    ///
+    /// Opening a stream never consumes credit, whichever frame is used.
+    pub fn set_open_frame(&mut self, f: OpenFrame) {
+        self.open_frame = f
+    }
+
+    /// Keep the headers of the last `n` frames sent and received (0 disables).
+    ///
+    /// This is a debugging aid for interoperability problems. Every header is
+    /// checked against basic ordering invariants, e.g. that a stream is opened
+    /// with a SYN before any other frame of it and carries no data after a FIN
+    /// in the same direction. If a frame we send violates them, we panic with
+    /// the recorded headers. If a received frame does, they are logged and the
+    /// connection ends with `ConnectionError::FrameOrder`.
+    #[cfg(feature = "frame-history")]
+    pub fn set_frame_history(&mut self, n: usize) {
+        self.frame_history = n
+    }
+}
+
+/// The limits a connection enforces, as resolved from its `Config`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    receive_window: u32,
+    max_frame_body: usize,
+    max_buffer_size: usize,
+    max_num_streams: usize
+}
+
+impl Limits {
+    /// The receive window of each stream.
+    pub fn receive_window(&self) -> u32 {
+        self.receive_window
+    }
+
+    /// The largest data frame body accepted from the remote.
+    ///
+    /// Larger frames are a protocol violation and terminate the connection.
+    pub fn max_frame_body(&self) -> usize {
+        self.max_frame_body
+    }
+
+    /// The max. number of bytes buffered per stream.
+    pub fn max_buffer_size(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    /// The max. number of streams open at the same time.
+    pub fn max_num_streams(&self) -> usize {
+        self.max_num_streams
+    }
 }
 
+/// Resolves the configuration to use for a particular peer.
+///
+/// Servers may implement this to allocate resources per peer, e.g. based on
+/// its reputation, instead of using the same configuration for everyone.
+/// See `Builder::peer`.
+pub trait ConfigProvider {
+    /// Application-specific peer identifier.
+    type PeerId;
+
+    /// Return the configuration to use for `peer` given a base configuration.
+    fn config(&self, peer: &Self::PeerId, base: &Config) -> Config;
+}
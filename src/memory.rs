@@ -0,0 +1,141 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! In-memory duplex transport.
+//!
+//! Useful for running many `Connection`s on a single thread without any
+//! sockets involved, e.g. in tests, benchmarks or relays which bridge
+//! in-process peers.
+
+use bytes::BytesMut;
+use crate::{Config, Connection, Mode};
+use futures::{prelude::*, task};
+use parking_lot::Mutex;
+use std::{cmp::min, io, sync::Arc};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Create a pair of connected endpoints with unbounded buffers.
+pub fn pair() -> (Endpoint, Endpoint) {
+    pair_with_capacity(usize::MAX)
+}
+
+/// Create a pair of connected endpoints.
+///
+/// Each direction buffers at most `capacity` bytes; writers beyond this
+/// limit get `WouldBlock` until the reader has consumed some data.
+pub fn pair_with_capacity(capacity: usize) -> (Endpoint, Endpoint) {
+    let a = Arc::new(Mutex::new(Pipe::new(capacity)));
+    let b = Arc::new(Mutex::new(Pipe::new(capacity)));
+    let e1 = Endpoint { incoming: a.clone(), outgoing: b.clone() };
+    let e2 = Endpoint { incoming: b, outgoing: a };
+    (e1, e2)
+}
+
+impl Connection<Endpoint> {
+    /// Create a client and a server connection over an unbounded in-memory
+    /// transport (see `pair`), both using `cfg`.
+    pub fn pair(cfg: Config) -> (Self, Self) {
+        let (a, b) = pair();
+        (Connection::new(a, cfg.clone(), Mode::Client), Connection::new(b, cfg, Mode::Server))
+    }
+}
+
+#[derive(Debug)]
+struct Pipe {
+    buffer: BytesMut,
+    capacity: usize,
+    closed: bool,
+    reader: Option<task::Task>,
+    writer: Option<task::Task>
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Self {
+        Pipe { buffer: BytesMut::new(), capacity, closed: false, reader: None, writer: None }
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(t) = self.reader.take() {
+            t.notify()
+        }
+        if let Some(t) = self.writer.take() {
+            t.notify()
+        }
+    }
+}
+
+/// One end of an in-memory duplex connection.
+///
+/// Dropping an endpoint closes both directions, i.e. the remote reads EOF
+/// and its writes fail with `BrokenPipe`.
+#[derive(Debug)]
+pub struct Endpoint {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>
+}
+
+impl io::Read for Endpoint {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.incoming.lock();
+        if pipe.buffer.is_empty() {
+            if pipe.closed {
+                return Ok(0)
+            }
+            pipe.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+        let n = min(pipe.buffer.len(), buf.len());
+        buf[.. n].copy_from_slice(&pipe.buffer.split_to(n));
+        if let Some(t) = pipe.writer.take() {
+            t.notify()
+        }
+        Ok(n)
+    }
+}
+
+impl AsyncRead for Endpoint {}
+
+impl io::Write for Endpoint {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.outgoing.lock();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into())
+        }
+        let n = min(pipe.capacity - pipe.buffer.len(), buf.len());
+        if n == 0 && !buf.is_empty() {
+            pipe.writer = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+        pipe.buffer.extend_from_slice(&buf[.. n]);
+        if let Some(t) = pipe.reader.take() {
+            t.notify()
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for Endpoint {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.outgoing.lock().close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.incoming.lock().close();
+        self.outgoing.lock().close()
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! Connections over sockets, enabled by the `tokio-tcp` and `tokio-uds` features.
+//!
+//! The side which connects is always the client.
+
+use crate::{Config, Connection, Mode};
+use futures::prelude::*;
+use std::io;
+#[cfg(feature = "tokio-tcp")]
+use std::net::SocketAddr;
+#[cfg(all(unix, feature = "tokio-uds"))]
+use std::path::Path;
+#[cfg(feature = "tokio-tcp")]
+use tokio_tcp::TcpStream;
+#[cfg(all(unix, feature = "tokio-uds"))]
+use tokio_uds::UnixStream;
+
+#[cfg(feature = "tokio-tcp")]
+impl Connection<TcpStream> {
+    /// Connect to `addr` over TCP and create a client connection.
+    pub fn over_tcp(addr: &SocketAddr, cfg: Config) -> impl Future<Item=Self, Error=io::Error> {
+        TcpStream::connect(addr).map(move |socket| Connection::new(socket, cfg, Mode::Client))
+    }
+}
+
+#[cfg(all(unix, feature = "tokio-uds"))]
+impl Connection<UnixStream> {
+    /// Connect to the Unix domain socket at `path` and create a client connection.
+    pub fn over_unix_socket(path: &Path, cfg: Config) -> impl Future<Item=Self, Error=io::Error> {
+        UnixStream::connect(path).map(move |socket| Connection::new(socket, cfg, Mode::Client))
+    }
+}
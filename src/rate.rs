@@ -0,0 +1,38 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::clock::Timestamp;
+use std::time::Duration;
+
+/// Counts events per second and reports when a limit is exceeded.
+#[derive(Debug)]
+pub(crate) struct RateLimit {
+    limit: u32,
+    count: u32,
+    start: Timestamp
+}
+
+impl RateLimit {
+    pub(crate) fn new(limit: u32, now: Timestamp) -> Self {
+        RateLimit { limit, count: 0, start: now }
+    }
+
+    /// Record one event at `now` and return `false` if it exceeds the limit.
+    ///
+    /// If the clock has jumped, counting starts over.
+    pub(crate) fn check(&mut self, now: Timestamp) -> bool {
+        if now.duration_since(self.start).map(|d| d >= Duration::from_secs(1)).unwrap_or(true) {
+            self.start = now;
+            self.count = 0
+        }
+        self.count = self.count.saturating_add(1);
+        self.count <= self.limit
+    }
+}
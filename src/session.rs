@@ -0,0 +1,1389 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! The yamux protocol state machine.
+//!
+//! A `Session` knows about streams, windows, stream IDs and the frames which
+//! need to be sent, but it does not perform any I/O. Frames read from the
+//! remote are passed to `Session::handle_frame` and the resulting `Action`s
+//! are carried out by the caller, e.g. `Connection`.
+
+use crate::{
+    BufferProvider,
+    Config,
+    DEFAULT_CREDIT,
+    FIRST_CLIENT_STREAM_ID,
+    MAX_STREAM_ID,
+    FIRST_SERVER_STREAM_ID,
+    GlobalBuffers,
+    IdAllocator,
+    InboundStreamPolicy,
+    LengthMismatchPolicy,
+    OpenFrame,
+    SequentialIds,
+    WindowUpdateMode,
+    clock::{Clock, Timestamp},
+    connection::Mode,
+    error::ConnectionError,
+    event::SoftLimit,
+    frame::{
+        header::{ACK, FIN, Header, RST, SYN, Type, UNLIMITED},
+        Data,
+        Frame,
+        GoAway,
+        GoAwayReason,
+        Ping,
+        RawFrame,
+        WindowUpdate
+    },
+    rate::RateLimit,
+    snapshot::{FrameSnapshot, Snapshot, StreamSnapshot},
+    stats::Stats,
+    stream::{self, State, StreamEntry, CONNECTION_ID}
+};
+use bytes::Bytes;
+use log::{debug, error, trace};
+use nohash_hasher::IntMap;
+use rand;
+#[cfg(feature = "body-age")]
+use crate::snapshot::BodyAges;
+use std::{cmp::min, collections::{BTreeMap, BTreeSet, VecDeque}, fmt, sync::Arc, time::{Duration, Instant}};
+
+/// Max. number of resets per second sent in reply to frames for unknown
+/// streams after a GoAway has been sent.
+const MAX_DRAIN_RESETS_PER_SEC: u32 = 64;
+
+/// Max. number of our pings awaiting a pong.
+const MAX_OUTSTANDING_PINGS: usize = 8;
+
+/// Time after which a ping is no longer expected to be answered.
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Something the driver of a `Session` needs to do.
+#[derive(Debug)]
+pub(crate) enum Action {
+    /// Send the frame to the remote. The frame has been queued at the given
+    /// time.
+    Send(RawFrame, Instant),
+    /// The remote has opened a new stream.
+    Incoming(stream::Id),
+    /// The remote has answered one of our pings after the given time.
+    Pong(Duration),
+    /// The remote has sent a pong with a nonce we did not send.
+    UnexpectedPong(u32),
+    /// We have sent a GoAway for the given reason.
+    GoAway(GoAwayReason),
+    /// The remote has sent a GoAway with the given error code.
+    Terminate(u32)
+}
+
+/// The lifecycle of a connection.
+///
+/// States only ever advance in the order listed here. The `Session` performs
+/// all transitions; its driver only asks which state it is in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionState {
+    /// Streams can be opened and are accepted.
+    Open,
+    /// A GoAway has been sent. New inbound streams are refused but existing
+    /// streams continue to work.
+    Draining,
+    /// The connection is closed locally. Pending frames are sent before the
+    /// underlying resource is closed.
+    Closing,
+    /// The connection is gone.
+    Closed
+}
+
+pub(crate) struct Session {
+    mode: Mode,
+    config: Config,
+    streams: BTreeMap<stream::Id, StreamEntry>,
+    actions: VecDeque<Action>,
+    pings: IntMap<u32, Timestamp>,
+    clock: Clock,
+    ids: Box<dyn IdAllocator>,
+    /// The lowest ID the next stream may have.
+    next_id: u32,
+    /// The ID of an abandoned stream to use again for the next stream.
+    reuse: Option<stream::Id>,
+    state: ConnectionState,
+    resets: Option<RateLimit>,
+    inbound: Option<RateLimit>,
+    drain_resets: RateLimit,
+    buffers: Arc<dyn BufferProvider>,
+    /// Soft limits which have been reported and not been left since.
+    over_streams: bool,
+    over_ids: bool,
+    over_buffers: BTreeSet<stream::Id>
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Session {{ \
+                mode: {:?}, \
+                state: {:?}, \
+                streams: {}, \
+                actions: {}, \
+                next_id: {} \
+            }}",
+            self.mode,
+            self.state,
+            self.streams.len(),
+            self.actions.len(),
+            self.next_id
+        )
+    }
+}
+
+impl Session {
+    pub(crate) fn new(config: Config, mode: Mode) -> Self {
+        let mut clock = Clock::new();
+        let now = clock.now();
+        let resets = config.max_resets_per_sec.map(|n| RateLimit::new(n, now));
+        let inbound = config.max_inbound_streams_per_sec.map(|n| RateLimit::new(n, now));
+        Session {
+            mode,
+            config,
+            streams: BTreeMap::new(),
+            actions: VecDeque::new(),
+            pings: IntMap::default(),
+            clock,
+            ids: Box::new(SequentialIds::new(mode)),
+            next_id: match mode {
+                Mode::Client => FIRST_CLIENT_STREAM_ID,
+                Mode::Server => FIRST_SERVER_STREAM_ID
+            },
+            reuse: None,
+            state: ConnectionState::Open,
+            resets,
+            inbound,
+            drain_resets: RateLimit::new(MAX_DRAIN_RESETS_PER_SEC, now),
+            buffers: Arc::new(GlobalBuffers),
+            over_streams: false,
+            over_ids: false,
+            over_buffers: BTreeSet::new()
+        }
+    }
+
+    /// Allocate the bodies of outbound data frames with `buffers`.
+    pub(crate) fn set_buffer_provider(&mut self, buffers: Arc<dyn BufferProvider>) {
+        self.buffers = buffers
+    }
+
+    /// Choose the IDs of outbound streams with `ids`.
+    pub(crate) fn set_id_allocator(&mut self, ids: Box<dyn IdAllocator>) {
+        self.ids = ids
+    }
+
+    pub(crate) fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub(crate) fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub(crate) fn stream(&self, id: stream::Id) -> Option<&StreamEntry> {
+        self.streams.get(&id)
+    }
+
+    pub(crate) fn num_streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Create a snapshot of this session's state.
+    ///
+    /// Only the protocol state is filled in, the driver adds its own.
+    pub(crate) fn snapshot(&self, with_bodies: bool) -> Snapshot {
+        Snapshot {
+            mode: self.mode,
+            state: self.state,
+            next_id: self.next_id,
+            incoming: 0,
+            stats: Stats::default(),
+            streams: self.streams.iter().map(|(&id, s)| StreamSnapshot::new(id, s)).collect(),
+            pending: self.actions.iter()
+                .filter_map(|a| match a {
+                    Action::Send(frame, _) => Some(FrameSnapshot::new(frame, with_bodies)),
+                    _ => None
+                })
+                .collect(),
+            #[cfg(feature = "body-age")]
+            body_ages: BodyAges {
+                stream_queue: BodyAges::oldest(self.actions.iter().filter_map(|a| match a {
+                    Action::Send(frame, queued) if !frame.body.is_empty() => Some(*queued),
+                    _ => None
+                })),
+                .. BodyAges::default()
+            }
+        }
+    }
+
+    /// Process a frame received from the remote.
+    ///
+    /// Returns the actions resulting from it, including any which have been
+    /// queued before and not yet taken with `poll_actions`.
+    pub(crate) fn handle_frame(&mut self, frame: RawFrame) -> Vec<Action> {
+        if self.state == ConnectionState::Closed {
+            return Vec::new()
+        }
+        let response = match frame.dyn_type() {
+            Type::Data => self.on_data(&Frame::assert(frame)),
+            Type::WindowUpdate => self.on_window_update(&Frame::assert(frame)),
+            Type::Ping => {
+                if let Some(pong) = self.on_ping(&Frame::assert(frame)) {
+                    self.actions.push_back(Action::Send(pong.into_raw(), Instant::now()))
+                }
+                None
+            }
+            Type::GoAway => {
+                let code = Frame::<GoAway>::assert(frame).header().error_code();
+                self.terminate();
+                return vec![Action::Terminate(code)]
+            }
+        };
+        if let Some(reason) = response {
+            self.go_away(reason);
+        }
+        self.poll_actions()
+    }
+
+    /// Take the actions which have been queued by calls to this session.
+    pub(crate) fn poll_actions(&mut self) -> Vec<Action> {
+        self.actions.drain(..).collect()
+    }
+
+    /// Read the clock all timeouts of the connection are based on.
+    pub(crate) fn now(&mut self) -> Timestamp {
+        self.clock.now()
+    }
+
+    /// Open a new outbound stream.
+    pub(crate) fn open_stream(&mut self) -> Result<stream::Id, ConnectionError> {
+        if self.state >= ConnectionState::Closing {
+            return Err(ConnectionError::Closed)
+        }
+        if self.streams.len() >= self.config.max_num_streams {
+            error!("maximum number of streams reached");
+            return Err(ConnectionError::TooManyStreams)
+        }
+        let id = self.next_stream_id()?;
+        match self.config.open_frame {
+            OpenFrame::WindowUpdate => {
+                let mut frame = Frame::window_update(id, self.config.receive_window);
+                frame.header_mut().syn();
+                if self.config.unlimited_streams {
+                    frame.header_mut().unlimited()
+                }
+                self.actions.push_back(Action::Send(frame.into_raw(), Instant::now()))
+            }
+            OpenFrame::Data => {
+                let mut frame = Frame::data(id, Bytes::new());
+                frame.header_mut().syn();
+                if self.config.unlimited_streams {
+                    frame.header_mut().unlimited()
+                }
+                self.actions.push_back(Action::Send(frame.into_raw(), Instant::now()));
+                if self.config.receive_window > DEFAULT_CREDIT {
+                    let frame = Frame::window_update(id, self.config.receive_window - DEFAULT_CREDIT);
+                    self.actions.push_back(Action::Send(frame.into_raw(), Instant::now()))
+                }
+            }
+        }
+        let stream = StreamEntry::new(self.config.receive_window, DEFAULT_CREDIT);
+        self.streams.insert(id, stream);
+        Ok(id)
+    }
+
+    /// Check which configured limits are about to be reached.
+    ///
+    /// Only limits which have not been reported before, or have been left
+    /// since, are returned (see `SoftLimit`). `id` is the stream which has
+    /// received data, if any.
+    pub(crate) fn soft_limits(&mut self, id: Option<stream::Id>) -> Vec<SoftLimit> {
+        let mut limits = Vec::new();
+
+        let max = self.config.max_num_streams;
+        let is_over = self.streams.len().saturating_mul(5) > max.saturating_mul(4);
+        if is_over && !self.over_streams {
+            limits.push(SoftLimit::Streams { open: self.streams.len(), max })
+        }
+        self.over_streams = is_over;
+
+        if !self.over_ids && self.next_id > MAX_STREAM_ID / 10 * 9 {
+            self.over_ids = true;
+            limits.push(SoftLimit::StreamIds { next: self.next_id })
+        }
+
+        let streams = &self.streams;
+        self.over_buffers.retain(|id| streams.contains_key(id));
+        if let Some((id, stream)) = id.and_then(|id| self.streams.get(&id).map(|s| (id, s))) {
+            let max = self.config.max_buffer_size;
+            let buffered = stream.buffer.lock().len();
+            if buffered.saturating_mul(5) <= max.saturating_mul(4) {
+                self.over_buffers.remove(&id);
+            } else if self.over_buffers.insert(id) {
+                limits.push(SoftLimit::Buffer { stream: id.as_u32(), buffered, max })
+            }
+        }
+
+        limits
+    }
+
+    /// Send a ping with a random nonce to the remote.
+    ///
+    /// Pings which have not been answered within `PING_TIMEOUT` are
+    /// considered lost and no longer count as outstanding. Pongs arriving
+    /// for them later are unexpected. If the clock has jumped, the timeout
+    /// starts over for pings sent before.
+    pub(crate) fn ping(&mut self) -> Result<(), ConnectionError> {
+        let now = self.clock.now();
+        self.pings.retain(|_, sent| {
+            match now.duration_since(*sent) {
+                Some(d) => d < PING_TIMEOUT,
+                None => {
+                    *sent = now;
+                    true
+                }
+            }
+        });
+        if self.pings.len() >= MAX_OUTSTANDING_PINGS {
+            return Err(ConnectionError::TooManyPings)
+        }
+        let nonce = loop {
+            let n = rand::random();
+            if !self.pings.contains_key(&n) {
+                break n
+            }
+        };
+        self.pings.insert(nonce, now);
+        let mut header = Header::ping(nonce);
+        header.syn();
+        self.actions.push_back(Action::Send(Frame::new(header).into_raw(), Instant::now()));
+        Ok(())
+    }
+
+    /// Queue a data frame with as much of `data` as the stream's credit allows.
+    ///
+    /// Streams with unlimited credit send at most `DEFAULT_CREDIT` bytes per
+    /// frame. Returns the number of bytes queued.
+    pub(crate) fn send_data(&mut self, id: stream::Id, data: &[u8]) -> Result<usize, ConnectionError> {
+        let stream = self.streams.get_mut(&id).ok_or(ConnectionError::StreamNotFound(id))?;
+        let n =
+            if stream.unlimited {
+                min(DEFAULT_CREDIT as usize, data.len())
+            } else {
+                let n = min(stream.credit as usize, data.len());
+                stream.credit = stream.credit.saturating_sub(n as u32);
+                n
+            };
+        let mut body = self.buffers.alloc(n);
+        body.extend_from_slice(&data[.. n]);
+        let frame = Frame::data(id, body.freeze());
+        self.actions.push_back(Action::Send(frame.into_raw(), Instant::now()));
+        Ok(n)
+    }
+
+    /// Account for `n` bytes the application has read from a stream.
+    ///
+    /// Once half of the receive window has been consumed, the consumed bytes are
+    /// credited back to the remote. Returns `true` if a window update is pending.
+    pub(crate) fn on_read(&mut self, id: stream::Id, n: usize) -> bool {
+        if self.config.window_update_mode != WindowUpdateMode::OnRead {
+            return false
+        }
+        if let Some(stream) = self.streams.get_mut(&id) {
+            if stream.unlimited {
+                return false
+            }
+            stream.consumed = stream.consumed.saturating_add(min(n, u32::MAX as usize) as u32);
+            if stream.consumed >= self.config.receive_window / 2 {
+                trace!("{:?}: read: stream {}: sending window update", self.mode, id);
+                let frame = Frame::window_update(id, stream.consumed);
+                self.actions.push_back(Action::Send(frame.into_raw(), Instant::now()));
+                stream.window = stream.window.saturating_add(stream.consumed);
+                stream.consumed = 0;
+                return true
+            }
+        }
+        false
+    }
+
+    /// Half-close a stream by sending a FIN to the remote.
+    ///
+    /// Nothing is sent if the stream has been half-closed already.
+    pub(crate) fn finish(&mut self, id: stream::Id) -> Result<(), ConnectionError> {
+        let stream = self.streams.get_mut(&id).ok_or(ConnectionError::StreamNotFound(id))?;
+        match stream.state() {
+            State::SendClosed | State::Closed => return Ok(()),
+            State::Open | State::RecvClosed => stream.update_state(State::SendClosed)
+        }
+        let mut header = Header::data(id, 0);
+        header.fin();
+        self.actions.push_back(Action::Send(Frame::new(header).into_raw(), Instant::now()));
+        Ok(())
+    }
+
+    /// Remove a stream and inform the remote about it.
+    ///
+    /// Streams which have been half-closed in both directions are removed
+    /// without a reset.
+    pub(crate) fn reset(&mut self, id: stream::Id) {
+        match self.streams.remove(&id) {
+            None => return,
+            Some(ref s) if s.state() == State::Closed => {
+                trace!("{:?}: removing closed stream {}", self.mode, id);
+                return
+            }
+            Some(_) => {}
+        }
+        debug!("resetting stream {}: {:?}", id, self);
+        self.send_reset(id);
+        if self.state != ConnectionState::Open || !self.is_valid_remote_id(id, Type::Data) {
+            return
+        }
+        let now = self.clock.now();
+        if self.resets.as_mut().map(|r| !r.check(now)).unwrap_or(false) {
+            error!("too many inbound streams reset, refusing new ones");
+            self.go_away(GoAwayReason::TOO_MANY_RESETS);
+        }
+    }
+
+    /// Forget an outbound stream the remote has not been told about yet.
+    ///
+    /// Any queued frames of the stream are dropped and no reset is sent. The
+    /// stream ID is reused if no other stream has been opened since.
+    pub(crate) fn abandon(&mut self, id: stream::Id) {
+        if self.streams.remove(&id).is_none() {
+            return
+        }
+        debug!("abandoning stream {}: {:?}", id, self);
+        self.actions.retain(|a| match a {
+            Action::Send(frame, _) => frame.header.stream_id != id,
+            _ => true
+        });
+        if self.next_id.checked_sub(2) == Some(id.as_u32()) {
+            self.next_id = id.as_u32();
+            self.reuse = Some(id)
+        }
+    }
+
+    /// Inform the remote that this session is terminating.
+    ///
+    /// No new inbound streams are accepted afterwards. Only the first GoAway
+    /// is sent, later ones (e.g. for further protocol errors) are dropped.
+    /// Returns `false` in this case.
+    pub(crate) fn go_away(&mut self, reason: GoAwayReason) -> bool {
+        if self.state != ConnectionState::Open {
+            trace!("{:?}: not sending go away ({}): {:?}", self.mode, reason, self.state);
+            return false
+        }
+        debug!("{:?}: sending go away: {}", self.mode, reason);
+        self.state = ConnectionState::Draining;
+        self.actions.push_back(Action::Send(Frame::go_away_for(reason).into_raw(), Instant::now()));
+        self.actions.push_back(Action::GoAway(reason));
+        true
+    }
+
+    /// Start closing this session locally.
+    ///
+    /// Unless sent already, a GoAway is queued. The driver is expected to send
+    /// all pending frames, close the underlying connection and `terminate`.
+    pub(crate) fn close(&mut self) {
+        if self.state >= ConnectionState::Closing {
+            return
+        }
+        self.go_away(GoAwayReason::CLOSED);
+        self.state = ConnectionState::Closing
+    }
+
+    /// Discard all streams and queued actions.
+    ///
+    /// Called when the underlying connection is gone. Returns `true` only on
+    /// the first call, any further calls are without effect.
+    pub(crate) fn terminate(&mut self) -> bool {
+        if self.state == ConnectionState::Closed {
+            return false
+        }
+        self.state = ConnectionState::Closed;
+        self.streams.clear();
+        self.actions.clear();
+        true
+    }
+
+    fn next_stream_id(&mut self) -> Result<stream::Id, ConnectionError> {
+        let proposed = match self.reuse.take() {
+            Some(id) => id,
+            None => {
+                let id = stream::Id::new(self.ids.next_id().ok_or(ConnectionError::NoMoreStreamIds)?);
+                let valid = match self.mode {
+                    Mode::Client => id.is_client(),
+                    Mode::Server => id.is_server()
+                };
+                // IDs must increase so that closed streams are never reopened.
+                if !valid || id.is_session() || id.as_u32() < self.next_id {
+                    error!("invalid stream id {} for {:?}", id, self.mode);
+                    return Err(ConnectionError::InvalidStreamId(id))
+                }
+                id
+            }
+        };
+        self.next_id = proposed.as_u32().checked_add(2).ok_or(ConnectionError::NoMoreStreamIds)?;
+        Ok(proposed)
+    }
+
+    fn is_valid_remote_id(&self, id: stream::Id, ty: Type) -> bool {
+        match ty {
+            Type::Ping | Type::GoAway => return id.is_session(),
+            _ => {}
+        }
+        match self.mode {
+            Mode::Client => id.is_server(),
+            Mode::Server => id.is_client()
+        }
+    }
+
+    fn on_data(&mut self, frame: &Frame<Data>) -> Option<GoAwayReason> {
+        let stream_id = frame.header().id();
+
+        if frame.header().flags().contains(RST) { // stream reset
+            debug!("received reset for stream {}", stream_id);
+            self.streams.remove(&stream_id);
+            return None
+        }
+
+        if frame.header().len() as usize != frame.body().len() {
+            error!("stream {}: header length {} does not match body length {}",
+                stream_id,
+                frame.header().len(),
+                frame.body().len());
+            match self.config.length_mismatch_policy {
+                LengthMismatchPolicy::GoAway => return Some(GoAwayReason::LENGTH_MISMATCH),
+                LengthMismatchPolicy::Reset => {
+                    self.streams.remove(&stream_id);
+                    self.send_reset(stream_id);
+                    return None
+                }
+            }
+        }
+
+        let is_finish = frame.header().flags().contains(FIN); // half-close
+
+        if frame.header().flags().contains(SYN) { // new stream
+            if self.state != ConnectionState::Open {
+                debug!("refusing stream {}: go away has been sent", stream_id);
+                self.on_unknown_stream(stream_id);
+                return None
+            }
+            if !self.is_valid_remote_id(stream_id, Type::Data) {
+                error!("invalid stream id {}", stream_id);
+                return Some(GoAwayReason::INVALID_STREAM_ID)
+            }
+            if !self.check_inbound_rate(stream_id) {
+                return None
+            }
+            if frame.body().len() > DEFAULT_CREDIT as usize {
+                error!("initial data exceeds default credit");
+                return Some(GoAwayReason::INITIAL_DATA_TOO_LARGE)
+            }
+            if self.streams.contains_key(&stream_id) {
+                error!("stream {} already exists", stream_id);
+                return Some(GoAwayReason::DUPLICATE_STREAM)
+            }
+            if self.streams.len() == self.config.max_num_streams {
+                error!("maximum number of streams reached");
+                return Some(GoAwayReason::TOO_MANY_STREAMS)
+            }
+            let mut stream = StreamEntry::new(self.config.receive_window, DEFAULT_CREDIT);
+            if is_finish {
+                stream.update_state(State::RecvClosed)
+            }
+            if frame.header().flags().contains(UNLIMITED) {
+                self.accept_unlimited(stream_id, &mut stream)
+            }
+            stream.window = stream.window.saturating_sub(frame.body().len() as u32);
+            stream.buffer.lock().extend(frame.body());
+            self.streams.insert(stream_id, stream);
+            self.actions.push_back(Action::Incoming(stream_id));
+            return None
+        }
+
+        let reset_stream =
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                if !stream.unlimited && frame.body().len() > stream.window as usize {
+                    error!("frame body larger than window of stream {}", stream_id);
+                    return Some(GoAwayReason::WINDOW_EXCEEDED)
+                }
+                if is_finish {
+                    stream.update_state(State::RecvClosed)
+                }
+                if stream.buffer.lock().len() >= self.config.max_buffer_size {
+                    error!("buffer of stream {} grows beyond limit", stream_id);
+                    true
+                } else {
+                    stream.buffer.lock().extend(frame.body());
+                    if stream.unlimited {
+                        return None
+                    }
+                    stream.window = stream.window.saturating_sub(frame.body().len() as u32);
+                    if stream.window == 0 && self.config.window_update_mode == WindowUpdateMode::OnReceive {
+                        trace!("{:?}: stream {}: sending window update", self.mode, stream_id);
+                        let frame = Frame::window_update(stream_id, self.config.receive_window);
+                        self.actions.push_back(Action::Send(frame.into_raw(), Instant::now()));
+                        stream.window = self.config.receive_window
+                    }
+                    false
+                }
+            } else {
+                self.on_unknown_stream(stream_id);
+                false
+            };
+
+        if reset_stream {
+            self.reset(stream_id)
+        }
+
+        None
+    }
+
+    fn on_window_update(&mut self, frame: &Frame<WindowUpdate>) -> Option<GoAwayReason> {
+        let stream_id = frame.header().id();
+
+        if frame.header().flags().contains(RST) { // stream reset
+            debug!("received reset for stream {}", stream_id);
+            self.streams.remove(&stream_id);
+            return None
+        }
+
+        let is_finish = frame.header().flags().contains(FIN); // half-close
+
+        if frame.header().flags().contains(SYN) { // new stream
+            if self.state != ConnectionState::Open {
+                debug!("refusing stream {}: go away has been sent", stream_id);
+                self.on_unknown_stream(stream_id);
+                return None
+            }
+            if !self.is_valid_remote_id(stream_id, Type::WindowUpdate) {
+                error!("invalid stream id {}", stream_id);
+                return Some(GoAwayReason::INVALID_STREAM_ID)
+            }
+            if !self.check_inbound_rate(stream_id) {
+                return None
+            }
+            if self.streams.contains_key(&stream_id) {
+                error!("stream {} already exists", stream_id);
+                return Some(GoAwayReason::DUPLICATE_STREAM)
+            }
+            if self.streams.len() == self.config.max_num_streams {
+                error!("maximum number of streams reached");
+                return Some(GoAwayReason::TOO_MANY_STREAMS)
+            }
+            // Remotes following the spec announce an increment over the
+            // default credit, those running this crate their whole receive
+            // window, which is never below the default credit. Taking an
+            // increment for a whole window only ever gives us less credit.
+            let announced = frame.header().credit();
+            let credit = if announced < DEFAULT_CREDIT { DEFAULT_CREDIT + announced } else { announced };
+            let mut stream = StreamEntry::new(self.config.receive_window, credit);
+            if is_finish {
+                stream.update_state(State::RecvClosed)
+            }
+            if frame.header().flags().contains(UNLIMITED) {
+                self.accept_unlimited(stream_id, &mut stream)
+            }
+            self.streams.insert(stream_id, stream);
+            self.actions.push_back(Action::Incoming(stream_id));
+            return None
+        }
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if frame.header().flags().contains(ACK.and(UNLIMITED)) && self.config.unlimited_streams {
+                trace!("{:?}: stream {}: remote agreed to unlimited credit", self.mode, stream_id);
+                stream.unlimited = true
+            }
+            if !stream.unlimited {
+                if let Some(credit) = stream.credit.checked_add(frame.header().credit()) {
+                    stream.credit = credit
+                } else {
+                    error!("credit of stream {} exceeds max. window size", stream_id);
+                    return Some(GoAwayReason::CREDIT_OVERFLOW)
+                }
+            }
+            if is_finish {
+                stream.update_state(State::RecvClosed)
+            }
+        } else {
+            self.on_unknown_stream(stream_id)
+        }
+
+        None
+    }
+
+    fn on_ping(&mut self, frame: &Frame<Ping>) -> Option<Frame<Ping>> {
+        let stream_id = frame.header().id();
+
+        if frame.header().flags().contains(ACK) { // pong
+            let nonce = frame.header().nonce();
+            match self.pings.remove(&nonce) {
+                Some(start) => match self.clock.now().duration_since(start) {
+                    Some(rtt) => self.actions.push_back(Action::Pong(rtt)),
+                    None => debug!("pong {}: clock jumped, round-trip time unknown", nonce)
+                },
+                None => self.actions.push_back(Action::UnexpectedPong(nonce))
+            }
+            return None
+        }
+
+        if stream_id == CONNECTION_ID || self.streams.contains_key(&stream_id) {
+            let mut hdr = Header::ping(frame.header().nonce());
+            hdr.ack();
+            return Some(Frame::new(hdr))
+        }
+
+        debug!("received ping for unknown stream {}", stream_id);
+        None
+    }
+
+    /// Handle a frame for a stream which does not exist (any more).
+    ///
+    /// Such frames are usually ignored, but once we have sent a GoAway the
+    /// remote may still be writing to streams we have closed or opening new
+    /// ones, which we refuse. Resetting these streams lets it learn about this
+    /// promptly. At most `MAX_DRAIN_RESETS_PER_SEC` resets are sent, so that a
+    /// remote which keeps opening streams does not make us send one each.
+    fn on_unknown_stream(&mut self, id: stream::Id) {
+        if self.state == ConnectionState::Open {
+            return
+        }
+        if !self.drain_resets.check(self.clock.now()) {
+            trace!("{:?}: not resetting unknown stream {}: rate limit", self.mode, id);
+            return
+        }
+        debug!("resetting unknown stream {} while draining", id);
+        self.send_reset(id)
+    }
+
+    /// Record an attempt of the remote to open a stream.
+    ///
+    /// Returns `false` if the stream exceeds the configured rate and must be
+    /// refused, in which case the inbound stream policy has been applied.
+    fn check_inbound_rate(&mut self, id: stream::Id) -> bool {
+        let now = self.clock.now();
+        if self.inbound.as_mut().map(|r| r.check(now)).unwrap_or(true) {
+            return true
+        }
+        match self.config.inbound_stream_policy {
+            InboundStreamPolicy::Reset => {
+                debug!("resetting stream {}: remote opens streams too fast", id);
+                self.send_reset(id)
+            }
+            InboundStreamPolicy::GoAway => {
+                error!("remote opens streams too fast, refusing new ones");
+                self.go_away(GoAwayReason::INBOUND_RATE_EXCEEDED);
+            }
+        }
+        false
+    }
+
+    /// Answer a remote's request to disable flow control for a new stream.
+    ///
+    /// If unlimited streams are disabled the request is ignored and the stream
+    /// keeps its windows. Otherwise we acknowledge, and since the remote only
+    /// stops counting credit once it sees the acknowledgement, we may stop
+    /// enforcing the receive window right away.
+    fn accept_unlimited(&mut self, id: stream::Id, stream: &mut StreamEntry) {
+        if !self.config.unlimited_streams {
+            debug!("{:?}: stream {}: unlimited streams are disabled", self.mode, id);
+            return
+        }
+        stream.unlimited = true;
+        let mut frame = Frame::window_update(id, 0);
+        frame.header_mut().ack();
+        frame.header_mut().unlimited();
+        self.actions.push_back(Action::Send(frame.into_raw(), Instant::now()))
+    }
+
+    fn send_reset(&mut self, id: stream::Id) {
+        let mut header = Header::data(id, 0);
+        header.rst();
+        self.actions.push_back(Action::Send(Frame::new(header).into_raw(), Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::frame::{codec::FrameCodec, header::ECODE_PROTO};
+    use super::*;
+    use tokio_codec::Encoder;
+
+    fn syn(id: u32) -> RawFrame {
+        let mut frame = Frame::window_update(stream::Id::new(id), DEFAULT_CREDIT);
+        frame.header_mut().syn();
+        frame.into_raw()
+    }
+
+    #[test]
+    fn inbound_stream_is_announced() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        match session.handle_frame(syn(1)).as_slice() {
+            [Action::Incoming(id)] => assert_eq!(stream::Id::new(1), *id),
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        assert_eq!(Some(DEFAULT_CREDIT), session.stream(stream::Id::new(1)).map(|s| s.credit))
+    }
+
+    #[test]
+    fn allocated_ids_are_validated() {
+        struct Fixed(Vec<u32>);
+
+        impl IdAllocator for Fixed {
+            fn next_id(&mut self) -> Option<u32> {
+                self.0.pop()
+            }
+        }
+
+        let mut session = Session::new(Config::default(), Mode::Client);
+        session.set_id_allocator(Box::new(Fixed(vec![5, 7, 0, 4, 7])));
+        assert_eq!(stream::Id::new(7), session.open_stream().unwrap());
+        for _ in 0 .. 4 {
+            match session.open_stream() {
+                Err(ConnectionError::InvalidStreamId(_)) => {}
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        match session.open_stream() {
+            Err(ConnectionError::NoMoreStreamIds) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert_eq!(1, session.streams.len())
+    }
+
+    #[test]
+    fn abandoned_ids_are_reused() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        let id = session.open_stream().unwrap();
+        session.abandon(id);
+        assert_eq!(id, session.open_stream().unwrap());
+        assert_eq!(stream::Id::new(4), session.open_stream().unwrap())
+    }
+
+    #[test]
+    fn invalid_stream_id_is_a_protocol_error() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        match session.handle_frame(syn(2)).as_slice() {
+            [Action::Send(frame, _), Action::GoAway(reason)] => {
+                assert_eq!(Type::GoAway, frame.header.typ);
+                assert_eq!(ECODE_PROTO, Frame::<GoAway>::assert(frame.clone()).header().error_code());
+                assert_eq!(GoAwayReason::INVALID_STREAM_ID, *reason)
+            }
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        match session.handle_frame(syn(1)).as_slice() {
+            [Action::Send(frame, _)] => assert!(frame.header.flags.contains(RST), "no new streams after go away"),
+            other => panic!("unexpected actions: {:?}", other)
+        }
+    }
+
+    fn inbound_rate_limited(policy: InboundStreamPolicy) -> Session {
+        let mut cfg = Config::default();
+        cfg.set_max_inbound_streams_per_sec(Some(2));
+        cfg.set_inbound_stream_policy(policy);
+        Session::new(cfg, Mode::Server)
+    }
+
+    #[test]
+    fn excess_inbound_streams_are_reset() {
+        let mut session = inbound_rate_limited(InboundStreamPolicy::Reset);
+        assert_eq!(1, session.handle_frame(syn(1)).len());
+        assert_eq!(1, session.handle_frame(syn(3)).len());
+        match session.handle_frame(syn(5)).as_slice() {
+            [Action::Send(frame, _)] => {
+                assert_eq!(stream::Id::new(5), frame.header.stream_id);
+                assert!(frame.header.flags.contains(RST))
+            }
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        assert!(session.stream(stream::Id::new(5)).is_none())
+    }
+
+    #[test]
+    fn excess_inbound_streams_cause_go_away() {
+        let mut session = inbound_rate_limited(InboundStreamPolicy::GoAway);
+        session.handle_frame(syn(1));
+        session.handle_frame(syn(3));
+        match session.handle_frame(syn(5)).as_slice() {
+            [Action::Send(frame, _), Action::GoAway(GoAwayReason::INBOUND_RATE_EXCEEDED)] => {
+                assert_eq!(Type::GoAway, frame.header.typ)
+            }
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        assert!(session.stream(stream::Id::new(5)).is_none())
+    }
+
+    #[test]
+    fn streams_opened_after_go_away_are_reset_at_a_limited_rate() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        assert!(session.go_away(GoAwayReason::CLOSED));
+        session.poll_actions();
+        let mut resets = 0;
+        for i in 0 .. 2 * MAX_DRAIN_RESETS_PER_SEC {
+            let id = 2 * i + 1;
+            match session.handle_frame(syn(id)).as_slice() {
+                [Action::Send(frame, _)] => {
+                    assert!(frame.header.flags.contains(RST));
+                    assert_eq!(id, frame.header.stream_id.as_u32());
+                    resets += 1
+                }
+                [] => {}
+                other => panic!("unexpected actions: {:?}", other)
+            }
+            assert!(session.stream(stream::Id::new(id)).is_none())
+        }
+        assert_eq!(MAX_DRAIN_RESETS_PER_SEC, resets)
+    }
+
+    /// A data frame whose header claims one byte more than its body has.
+    fn mismatched_data(id: u32, syn: bool) -> RawFrame {
+        let mut frame = Frame::data(stream::Id::new(id), Bytes::from("hello")).into_raw();
+        frame.header.length.0 += 1;
+        if syn {
+            frame.header.flags = frame.header.flags.and(SYN)
+        }
+        frame
+    }
+
+    #[test]
+    fn length_mismatch_is_a_protocol_error() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        session.handle_frame(syn(1));
+        match session.handle_frame(mismatched_data(1, false)).as_slice() {
+            [Action::Send(frame, _), Action::GoAway(GoAwayReason::LENGTH_MISMATCH)] => {
+                assert_eq!(Type::GoAway, frame.header.typ);
+                assert_eq!(ECODE_PROTO, Frame::<GoAway>::assert(frame.clone()).header().error_code())
+            }
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        assert_eq!(Some(0), session.stream(stream::Id::new(1)).map(|s| s.buffer.lock().len()))
+    }
+
+    #[test]
+    fn length_mismatch_resets_stream() {
+        let mut cfg = Config::default();
+        cfg.set_length_mismatch_policy(LengthMismatchPolicy::Reset);
+        let mut session = Session::new(cfg, Mode::Server);
+        session.handle_frame(syn(1));
+        for &(id, syn) in &[(1, false), (3, true)] {
+            match session.handle_frame(mismatched_data(id, syn)).as_slice() {
+                [Action::Send(frame, _)] => assert!(frame.header.flags.contains(RST)),
+                other => panic!("unexpected actions: {:?}", other)
+            }
+            assert!(session.stream(stream::Id::new(id)).is_none())
+        }
+        assert_eq!(ConnectionState::Open, session.state())
+    }
+
+    #[test]
+    fn credit_overflow_is_a_protocol_error() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        session.handle_frame(syn(1));
+        let frame = Frame::window_update(stream::Id::new(1), u32::MAX - DEFAULT_CREDIT).into_raw();
+        assert!(session.handle_frame(frame).is_empty());
+        assert_eq!(Some(u32::MAX), session.stream(stream::Id::new(1)).map(|s| s.credit));
+        match session.handle_frame(Frame::window_update(stream::Id::new(1), 1).into_raw()).as_slice() {
+            [Action::Send(frame, _), Action::GoAway(GoAwayReason::CREDIT_OVERFLOW)] => {
+                assert_eq!(Type::GoAway, frame.header.typ)
+            }
+            other => panic!("unexpected actions: {:?}", other)
+        }
+    }
+
+    /// Hand the frames among `actions` to `session`.
+    fn deliver(actions: Vec<Action>, session: &mut Session) -> Vec<Action> {
+        actions.into_iter()
+            .filter_map(|a| match a {
+                Action::Send(frame, _) => Some(frame),
+                _ => None
+            })
+            .flat_map(|frame| session.handle_frame(frame))
+            .collect()
+    }
+
+    /// Open a stream with the given frame and return what is sent on the wire.
+    fn open_with(open_frame: OpenFrame, receive_window: u32) -> (Vec<u8>, Vec<Action>) {
+        let mut config = Config::default();
+        config.set_open_frame(open_frame);
+        config.set_receive_window(receive_window).unwrap();
+        let mut session = Session::new(config, Mode::Client);
+        let id = session.open_stream().unwrap();
+        assert_eq!(DEFAULT_CREDIT, session.stream(id).unwrap().credit);
+        let actions = session.poll_actions();
+        let mut bytes = BytesMut::new();
+        for action in &actions {
+            if let Action::Send(frame, _) = action {
+                FrameCodec::default().encode(frame.clone(), &mut bytes).unwrap()
+            }
+        }
+        (bytes.to_vec(), actions)
+    }
+
+    #[test]
+    fn data_less_opens_are_encoded_as_configured() {
+        // Version, type, flags, stream ID and length, all big-endian.
+        let (bytes, _) = open_with(OpenFrame::WindowUpdate, DEFAULT_CREDIT);
+        assert_eq!(vec![0, 1, 0, 1, 0, 0, 0, 1, 0, 4, 0, 0], bytes);
+        let (bytes, _) = open_with(OpenFrame::Data, DEFAULT_CREDIT);
+        assert_eq!(vec![0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0], bytes);
+        let (bytes, _) = open_with(OpenFrame::Data, 2 * DEFAULT_CREDIT);
+        assert_eq!(vec![0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 4, 0, 0], bytes)
+    }
+
+    /// The send credit a remote following the yamux specification, e.g. Go
+    /// yamux, assumes after our opening frames: the default credit plus the
+    /// credit of every window update.
+    fn spec_credit(actions: &[Action]) -> u32 {
+        actions.iter().fold(DEFAULT_CREDIT, |credit, action| match action {
+            Action::Send(frame, _) if frame.header.typ == Type::WindowUpdate => credit + frame.header.length.0,
+            _ => credit
+        })
+    }
+
+    #[test]
+    fn spec_remotes_see_the_receive_window_of_data_opens() {
+        assert_eq!(OpenFrame::Data, Config::default().open_frame);
+        for &window in &[DEFAULT_CREDIT, DEFAULT_CREDIT + 1, 2 * DEFAULT_CREDIT, 16 * 1024 * 1024] {
+            let (_, actions) = open_with(OpenFrame::Data, window);
+            assert_eq!(window, spec_credit(&actions));
+            let (_, actions) = open_with(OpenFrame::WindowUpdate, window);
+            assert_eq!(window + DEFAULT_CREDIT, spec_credit(&actions), "documented excess credit")
+        }
+    }
+
+    #[test]
+    fn window_update_opens_of_spec_remotes_are_increments() {
+        // What e.g. Go yamux sends with the default window: no increment.
+        for &(announced, credit) in &[(0, DEFAULT_CREDIT), (1024, DEFAULT_CREDIT + 1024), (DEFAULT_CREDIT, DEFAULT_CREDIT)] {
+            let mut server = Session::new(Config::default(), Mode::Server);
+            let mut frame = Frame::window_update(stream::Id::new(1), announced);
+            frame.header_mut().syn();
+            match server.handle_frame(frame.into_raw()).as_slice() {
+                [Action::Incoming(id)] => assert_eq!(credit, server.stream(*id).unwrap().credit),
+                other => panic!("unexpected actions: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn data_less_opens_announce_the_receive_window() {
+        for &open_frame in &[OpenFrame::WindowUpdate, OpenFrame::Data] {
+            let mut server = Session::new(Config::default(), Mode::Server);
+            let (_, actions) = open_with(open_frame, 2 * DEFAULT_CREDIT);
+            match deliver(actions, &mut server).as_slice() {
+                [Action::Incoming(id)] => {
+                    let stream = server.stream(*id).unwrap();
+                    assert_eq!(2 * DEFAULT_CREDIT, stream.credit);
+                    assert_eq!(DEFAULT_CREDIT, stream.window);
+                    assert!(stream.buffer.lock().is_empty())
+                }
+                other => panic!("unexpected actions: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn unlimited_streams_are_negotiated() {
+        for &open_frame in &[OpenFrame::WindowUpdate, OpenFrame::Data] {
+            let mut config = Config::default();
+            config.set_unlimited_streams(true);
+            config.set_open_frame(open_frame);
+            let mut client = Session::new(config.clone(), Mode::Client);
+            let mut server = Session::new(config, Mode::Server);
+            let id = client.open_stream().unwrap();
+            assert!(!client.stream(id).unwrap().unlimited, "not before the remote agrees");
+
+            let replies = deliver(client.poll_actions(), &mut server);
+            assert!(server.stream(id).unwrap().unlimited);
+            assert!(deliver(replies, &mut client).is_empty());
+            assert!(client.stream(id).unwrap().unlimited);
+
+            let data = vec![0; 3 * DEFAULT_CREDIT as usize];
+            let mut sent = 0;
+            while sent < data.len() {
+                sent += client.send_data(id, &data[sent ..]).unwrap()
+            }
+            assert!(deliver(client.poll_actions(), &mut server).is_empty(), "no window updates");
+            assert_eq!(Some(data.len()), server.stream(id).map(|s| s.buffer.lock().len()));
+            assert!(!server.on_read(id, data.len()))
+        }
+    }
+
+    #[test]
+    fn unlimited_streams_need_the_remote_s_agreement() {
+        let mut config = Config::default();
+        config.set_unlimited_streams(true);
+        let mut client = Session::new(config, Mode::Client);
+        let mut server = Session::new(Config::default(), Mode::Server);
+        let id = client.open_stream().unwrap();
+
+        match deliver(client.poll_actions(), &mut server).as_slice() {
+            [Action::Incoming(i)] => assert_eq!(id, *i),
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        assert!(!server.stream(id).unwrap().unlimited);
+        assert!(!client.stream(id).unwrap().unlimited);
+        let data = vec![0; 2 * DEFAULT_CREDIT as usize];
+        assert_eq!(DEFAULT_CREDIT as usize, client.send_data(id, &data).unwrap());
+        assert_eq!(0, client.send_data(id, &data).unwrap())
+    }
+
+    #[test]
+    fn max_windows_are_ordinary_credit() {
+        let mut config = Config::default();
+        config.set_unlimited_streams(true);
+        let mut server = Session::new(config, Mode::Server);
+        let mut frame = Frame::window_update(stream::Id::new(1), u32::MAX);
+        frame.header_mut().syn();
+        match server.handle_frame(frame.into_raw()).as_slice() {
+            [Action::Incoming(id)] => {
+                let stream = server.stream(*id).unwrap();
+                assert!(!stream.unlimited);
+                assert_eq!(u32::MAX, stream.credit)
+            }
+            other => panic!("unexpected actions: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn soft_limits_are_reported_once() {
+        let mut config = Config::default();
+        config.set_max_num_streams(5);
+        config.set_max_buffer_size(10);
+        let mut session = Session::new(config, Mode::Server);
+        for id in &[1, 3, 5, 7] {
+            session.handle_frame(syn(*id));
+        }
+        assert!(session.soft_limits(None).is_empty());
+
+        session.handle_frame(syn(9));
+        assert_eq!(vec![SoftLimit::Streams { open: 5, max: 5 }], session.soft_limits(None));
+        assert!(session.soft_limits(None).is_empty());
+        session.handle_frame(Frame::<Data>::data(stream::Id::new(9), Bytes::from(vec![0; 9])).into_raw());
+        let id = Some(stream::Id::new(9));
+        assert_eq!(vec![SoftLimit::Buffer { stream: 9, buffered: 9, max: 10 }], session.soft_limits(id));
+        assert!(session.soft_limits(id).is_empty());
+
+        // Once left, limits are reported again when crossed.
+        session.reset(stream::Id::new(9));
+        session.stream(stream::Id::new(1)).unwrap().buffer.lock().extend_from_slice(&[0; 9]);
+        assert!(session.soft_limits(None).is_empty());
+        session.handle_frame(syn(11));
+        assert_eq!(vec![SoftLimit::Streams { open: 5, max: 5 }], session.soft_limits(None));
+        assert_eq!(1, session.soft_limits(Some(stream::Id::new(1))).len());
+
+        session.next_id = MAX_STREAM_ID - 1;
+        assert_eq!(vec![SoftLimit::StreamIds { next: MAX_STREAM_ID - 1 }], session.soft_limits(None));
+        assert!(session.soft_limits(None).is_empty());
+
+        // 80% of small limits are not rounded down.
+        let mut config = Config::default();
+        config.set_max_num_streams(2);
+        config.set_max_buffer_size(4);
+        let mut session = Session::new(config, Mode::Server);
+        session.handle_frame(syn(1));
+        session.handle_frame(Frame::<Data>::data(stream::Id::new(1), Bytes::from(vec![0; 3])).into_raw());
+        assert!(session.soft_limits(Some(stream::Id::new(1))).is_empty());
+        session.handle_frame(syn(3));
+        assert_eq!(vec![SoftLimit::Streams { open: 2, max: 2 }], session.soft_limits(None))
+    }
+
+    #[test]
+    fn pongs_must_match_outstanding_pings() {
+        let mut session = Session::new(Config::default(), Mode::Client);
+        session.ping().unwrap();
+        let nonce = match session.poll_actions().as_slice() {
+            [Action::Send(frame, _)] => {
+                assert!(frame.header.flags.contains(SYN));
+                frame.header.length.0
+            }
+            other => panic!("unexpected actions: {:?}", other)
+        };
+        let pong = |n| {
+            let mut header = Header::ping(n);
+            header.ack();
+            Frame::new(header).into_raw()
+        };
+        match session.handle_frame(pong(nonce.wrapping_add(1))).as_slice() {
+            [Action::UnexpectedPong(n)] => assert_eq!(nonce.wrapping_add(1), *n),
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        match session.handle_frame(pong(nonce)).as_slice() {
+            [Action::Pong(_)] => {}
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        match session.handle_frame(pong(nonce)).as_slice() { // answered already
+            [Action::UnexpectedPong(_)] => {}
+            other => panic!("unexpected actions: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn outstanding_pings_are_capped() {
+        let mut session = Session::new(Config::default(), Mode::Client);
+        for _ in 0 .. MAX_OUTSTANDING_PINGS {
+            session.ping().unwrap()
+        }
+        match session.ping() {
+            Err(ConnectionError::TooManyPings) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn lost_pings_expire() {
+        let mut session = Session::new(Config::default(), Mode::Client);
+        for _ in 0 .. MAX_OUTSTANDING_PINGS {
+            session.ping().unwrap()
+        }
+        // No pong arrives for the oldest ping.
+        let lost = match session.now().checked_sub(PING_TIMEOUT) {
+            Some(t) => t,
+            None => return // too early after boot to fake an old ping
+        };
+        let oldest = *session.pings.keys().next().unwrap();
+        session.pings.insert(oldest, lost);
+        session.ping().unwrap();
+        assert!(!session.pings.contains_key(&oldest));
+        assert_eq!(MAX_OUTSTANDING_PINGS, session.pings.len())
+    }
+
+    fn go_aways(actions: &[Action]) -> usize {
+        actions.iter().filter(|a| match a {
+            Action::Send(frame, _) => frame.header.typ == Type::GoAway,
+            _ => false
+        })
+        .count()
+    }
+
+    #[test]
+    fn reads_beyond_u32_are_clamped() {
+        let mut config = Config::default();
+        config.set_window_update_mode(WindowUpdateMode::OnRead);
+        let mut session = Session::new(config, Mode::Server);
+        session.handle_frame(syn(1));
+        assert!(session.on_read(stream::Id::new(1), usize::MAX));
+        match session.poll_actions().as_slice() {
+            [Action::Send(frame, _)] => assert_eq!(u32::MAX, frame.header.length.0),
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        assert_eq!(Some(u32::MAX), session.stream(stream::Id::new(1)).map(|s| s.window))
+    }
+
+    #[test]
+    fn sending_with_max_credit_keeps_the_rest() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        session.handle_frame(syn(1));
+        session.handle_frame(Frame::window_update(stream::Id::new(1), u32::MAX - DEFAULT_CREDIT).into_raw());
+        assert_eq!(3, session.send_data(stream::Id::new(1), b"abc").unwrap());
+        assert_eq!(Some(u32::MAX - 3), session.stream(stream::Id::new(1)).map(|s| s.credit))
+    }
+
+    #[test]
+    fn go_away_is_sent_once() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        session.handle_frame(syn(1));
+        assert!(session.go_away(GoAwayReason::CLOSED));
+        assert_eq!(ConnectionState::Draining, session.state());
+        assert!(!session.go_away(GoAwayReason::TOO_MANY_RESETS));
+        let overflow = Frame::window_update(stream::Id::new(1), u32::MAX).into_raw();
+        let actions = session.handle_frame(overflow); // protocol error while draining
+        assert_eq!(1, go_aways(&actions))
+    }
+
+    #[test]
+    fn close_sends_go_away_unless_draining() {
+        let mut session = Session::new(Config::default(), Mode::Client);
+        session.close();
+        assert_eq!(ConnectionState::Closing, session.state());
+        session.close();
+        assert_eq!(1, go_aways(&session.poll_actions()));
+        assert!(session.open_stream().is_err());
+
+        let mut session = Session::new(Config::default(), Mode::Client);
+        session.go_away(GoAwayReason::CLOSED);
+        session.close();
+        assert_eq!(ConnectionState::Closing, session.state());
+        assert_eq!(1, go_aways(&session.poll_actions()))
+    }
+
+    #[test]
+    fn terminate_is_idempotent() {
+        for &state in &[ConnectionState::Open, ConnectionState::Draining, ConnectionState::Closing] {
+            let mut session = Session::new(Config::default(), Mode::Server);
+            match state {
+                ConnectionState::Draining => { session.go_away(GoAwayReason::CLOSED); }
+                ConnectionState::Closing => session.close(),
+                _ => {}
+            }
+            assert_eq!(state, session.state());
+            assert!(session.terminate());
+            assert_eq!(ConnectionState::Closed, session.state());
+            assert!(session.poll_actions().is_empty());
+            assert!(!session.terminate());
+            session.close();
+            assert!(!session.go_away(GoAwayReason::CLOSED));
+            assert_eq!(ConnectionState::Closed, session.state());
+            assert!(session.handle_frame(syn(1)).is_empty())
+        }
+    }
+
+    #[test]
+    fn remote_go_away_closes_session() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        session.go_away(GoAwayReason::CLOSED);
+        match session.handle_frame(Frame::go_away(ECODE_PROTO).into_raw()).as_slice() {
+            [Action::Terminate(code)] => assert_eq!(ECODE_PROTO, *code),
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        assert_eq!(ConnectionState::Closed, session.state());
+        assert!(!session.terminate())
+    }
+
+    #[test]
+    fn local_actions_are_queued() {
+        let mut session = Session::new(Config::default(), Mode::Client);
+        let id = session.open_stream().unwrap();
+        assert_eq!(Ok(5), session.send_data(id, b"hello").map_err(|_| ()));
+        let actions = session.poll_actions();
+        assert_eq!(2, actions.len());
+        assert!(session.poll_actions().is_empty());
+        session.terminate();
+        assert!(session.send_data(id, b"hello").is_err())
+    }
+
+    #[test]
+    fn closed_streams_are_removed_without_reset() {
+        let mut session = Session::new(Config::default(), Mode::Server);
+        session.handle_frame(syn(1));
+        session.finish(stream::Id::new(1)).unwrap();
+        session.finish(stream::Id::new(1)).unwrap();
+        match session.poll_actions().as_slice() {
+            [Action::Send(frame, _)] => assert!(frame.header.flags.contains(FIN)),
+            other => panic!("unexpected actions: {:?}", other)
+        }
+        let mut fin = Header::data(stream::Id::new(1), 0);
+        fin.fin();
+        session.handle_frame(Frame::new(fin).into_raw());
+        assert_eq!(Some(State::Closed), session.stream(stream::Id::new(1)).map(|s| s.state()));
+        session.reset(stream::Id::new(1));
+        assert!(session.stream(stream::Id::new(1)).is_none());
+        assert!(session.poll_actions().is_empty())
+    }
+}
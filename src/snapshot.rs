@@ -0,0 +1,279 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use bytes::Bytes;
+use crate::{connection::Mode, frame::RawFrame, session::ConnectionState, stats::Stats, stream::{self, State, StreamEntry}};
+use std::fmt;
+#[cfg(feature = "body-age")]
+use std::time::{Duration, Instant};
+
+/// A snapshot of a connection's state, e.g. for attaching to bug reports.
+///
+/// The `Display` implementation renders the snapshot as JSON. Frame bodies are
+/// only included if requested explicitly (see `Connection::debug_dump_with_bodies`).
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub(crate) mode: Mode,
+    pub(crate) state: ConnectionState,
+    pub(crate) next_id: u32,
+    pub(crate) incoming: usize,
+    pub(crate) stats: Stats,
+    pub(crate) streams: Vec<StreamSnapshot>,
+    pub(crate) pending: Vec<FrameSnapshot>,
+    #[cfg(feature = "body-age")]
+    pub(crate) body_ages: BodyAges
+}
+
+impl Snapshot {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Has the connection been closed?
+    pub fn is_dead(&self) -> bool {
+        self.state == ConnectionState::Closed
+    }
+
+    /// Number of inbound streams not yet taken by the application.
+    pub fn incoming(&self) -> usize {
+        self.incoming
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// The open streams, ordered by ID.
+    pub fn streams(&self) -> &[StreamSnapshot] {
+        &self.streams
+    }
+
+    /// The frames waiting to be sent, in order.
+    pub fn pending(&self) -> &[FrameSnapshot] {
+        &self.pending
+    }
+
+    /// How long the oldest unsent frame bodies have been waiting.
+    #[cfg(feature = "body-age")]
+    pub fn body_ages(&self) -> BodyAges {
+        self.body_ages
+    }
+}
+
+/// The ages of the oldest frame bodies which have not been sent yet, by where
+/// they wait. Helps to find out where data gets stuck.
+///
+/// Ages are measured from when a stream has written the body.
+#[cfg(feature = "body-age")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BodyAges {
+    pub(crate) stream_queue: Option<Duration>,
+    pub(crate) connection_queue: Option<Duration>,
+    pub(crate) codec_buffer: Option<Duration>
+}
+
+#[cfg(feature = "body-age")]
+impl BodyAges {
+    /// The age of the oldest body written to a stream but not yet taken by
+    /// the connection.
+    pub fn stream_queue(&self) -> Option<Duration> {
+        self.stream_queue
+    }
+
+    /// The age of the oldest body in the connection's queue of frames.
+    pub fn connection_queue(&self) -> Option<Duration> {
+        self.connection_queue
+    }
+
+    /// The age of the oldest body handed to the codec but not yet flushed
+    /// to the underlying connection.
+    pub fn codec_buffer(&self) -> Option<Duration> {
+        self.codec_buffer
+    }
+
+    /// The time passed since the earliest of `instants`.
+    pub(crate) fn oldest<I>(instants: I) -> Option<Duration>
+    where
+        I: IntoIterator<Item=Instant>
+    {
+        instants.into_iter().min().map(|t| t.elapsed())
+    }
+}
+
+/// The state of a single stream.
+#[derive(Clone, Debug)]
+pub struct StreamSnapshot {
+    pub(crate) id: stream::Id,
+    pub(crate) state: State,
+    pub(crate) window: u32,
+    pub(crate) credit: u32,
+    pub(crate) consumed: u32,
+    pub(crate) buffered: usize
+}
+
+impl StreamSnapshot {
+    pub(crate) fn new(id: stream::Id, stream: &StreamEntry) -> Self {
+        StreamSnapshot {
+            id,
+            state: stream.state(),
+            window: stream.window,
+            credit: stream.credit,
+            consumed: stream.consumed,
+            buffered: stream.buffer.lock().len()
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id.as_u32()
+    }
+
+    /// Remaining receive window.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Remaining send credit.
+    pub fn credit(&self) -> u32 {
+        self.credit
+    }
+
+    /// Number of bytes read but not yet credited back to the remote.
+    pub fn consumed(&self) -> u32 {
+        self.consumed
+    }
+
+    /// Number of received bytes not yet read by the application.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+}
+
+/// A frame waiting to be sent.
+#[derive(Clone, Debug)]
+pub struct FrameSnapshot {
+    pub(crate) frame: RawFrame,
+    pub(crate) redacted: bool
+}
+
+impl FrameSnapshot {
+    pub(crate) fn new(frame: &RawFrame, with_body: bool) -> Self {
+        let mut frame = frame.clone();
+        if !with_body {
+            frame.body = Bytes::new()
+        }
+        FrameSnapshot { frame, redacted: !with_body }
+    }
+
+    pub fn stream_id(&self) -> u32 {
+        self.frame.header.stream_id.as_u32()
+    }
+
+    /// The length field of the frame header.
+    pub fn length(&self) -> u32 {
+        self.frame.header.length.0
+    }
+
+    /// The frame body, unless it has been redacted.
+    pub fn body(&self) -> Option<&[u8]> {
+        if self.redacted {
+            None
+        } else {
+            Some(&self.frame.body)
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{\"mode\":\"{:?}\",\"state\":\"{:?}\",\"next_id\":{},\"incoming\":{},",
+            self.mode,
+            self.state,
+            self.next_id,
+            self.incoming)?;
+        write!(f, "\"stats\":{{\"frames_sent\":{},\"frames_received\":{},\"bytes_sent\":{},\"bytes_received\":{},\
+                \"streams_opened\":{},\"streams_accepted\":{},\"resets_sent\":{},\"resets_received\":{},\
+                \"pings_sent\":{},\"unexpected_pongs\":{},\
+                \"transport_writes\":{},\"transport_bytes_written\":{}}},",
+            self.stats.frames_sent,
+            self.stats.frames_received,
+            self.stats.bytes_sent,
+            self.stats.bytes_received,
+            self.stats.streams_opened,
+            self.stats.streams_accepted,
+            self.stats.resets_sent,
+            self.stats.resets_received,
+            self.stats.pings_sent,
+            self.stats.unexpected_pongs,
+            self.stats.transport_writes,
+            self.stats.transport_bytes_written)?;
+        f.write_str("\"streams\":[")?;
+        for (i, s) in self.streams.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?
+            }
+            write!(f, "{}", s)?
+        }
+        f.write_str("],\"pending\":[")?;
+        for (i, p) in self.pending.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?
+            }
+            write!(f, "{}", p)?
+        }
+        f.write_str("]")?;
+        #[cfg(feature = "body-age")]
+        {
+            let millis = |d: Option<Duration>| match d {
+                Some(d) => (d.as_secs() * 1000 + u64::from(d.subsec_millis())).to_string(),
+                None => "null".to_string()
+            };
+            write!(f, ",\"body_ages_ms\":{{\"stream_queue\":{},\"connection_queue\":{},\"codec_buffer\":{}}}",
+                millis(self.body_ages.stream_queue),
+                millis(self.body_ages.connection_queue),
+                millis(self.body_ages.codec_buffer))?
+        }
+        f.write_str("}")
+    }
+}
+
+impl fmt::Display for StreamSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{\"id\":{},\"state\":\"{:?}\",\"window\":{},\"credit\":{},\"consumed\":{},\"buffered\":{}}}",
+            self.id,
+            self.state,
+            self.window,
+            self.credit,
+            self.consumed,
+            self.buffered)
+    }
+}
+
+impl fmt::Display for FrameSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = &self.frame.header;
+        write!(f, "{{\"type\":\"{:?}\",\"flags\":{},\"stream_id\":{},\"length\":{},\"body\":",
+            header.typ,
+            header.flags.0,
+            header.stream_id,
+            header.length.0)?;
+        if self.redacted {
+            return f.write_str("null}")
+        }
+        f.write_str("\"")?;
+        for b in &self.frame.body {
+            write!(f, "{:02x}", b)?
+        }
+        f.write_str("\"}")
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use futures::Poll;
+use std::{cmp::min, io, time::Duration};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A snapshot of connection statistics.
+///
+/// All counters are cumulative since the connection has been created.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub(crate) frames_sent: u64,
+    pub(crate) frames_received: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) streams_opened: u64,
+    pub(crate) streams_accepted: u64,
+    pub(crate) resets_sent: u64,
+    pub(crate) resets_received: u64,
+    pub(crate) pings_sent: u64,
+    pub(crate) unexpected_pongs: u64,
+    pub(crate) transport_writes: u64,
+    pub(crate) transport_bytes_written: u64,
+    pub(crate) queue_latency: Histogram,
+    pub(crate) rtt: Option<Duration>
+}
+
+impl Stats {
+    /// Number of frames written to the underlying connection.
+    pub fn frames_sent(&self) -> u64 {
+        self.frames_sent
+    }
+
+    /// Number of frames read from the underlying connection.
+    pub fn frames_received(&self) -> u64 {
+        self.frames_received
+    }
+
+    /// Number of data frame body bytes sent.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Number of data frame body bytes received.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Number of outbound streams opened.
+    pub fn streams_opened(&self) -> u64 {
+        self.streams_opened
+    }
+
+    /// Number of inbound streams accepted.
+    pub fn streams_accepted(&self) -> u64 {
+        self.streams_accepted
+    }
+
+    /// Number of stream resets sent.
+    pub fn resets_sent(&self) -> u64 {
+        self.resets_sent
+    }
+
+    /// Number of stream resets received.
+    pub fn resets_received(&self) -> u64 {
+        self.resets_received
+    }
+
+    /// Number of pings sent with `Connection::ping`.
+    pub fn pings_sent(&self) -> u64 {
+        self.pings_sent
+    }
+
+    /// Number of pongs received which do not answer any of our pings.
+    pub fn unexpected_pongs(&self) -> u64 {
+        self.unexpected_pongs
+    }
+
+    /// The round-trip time measured by the most recently answered ping.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Number of successful writes to the underlying connection.
+    pub fn transport_writes(&self) -> u64 {
+        self.transport_writes
+    }
+
+    /// Number of bytes written to the underlying connection, including
+    /// frame headers.
+    pub fn transport_bytes_written(&self) -> u64 {
+        self.transport_bytes_written
+    }
+
+    /// Average number of frames per write to the underlying connection.
+    pub fn frames_per_write(&self) -> Option<f64> {
+        if self.transport_writes == 0 {
+            return None
+        }
+        Some(self.frames_sent as f64 / self.transport_writes as f64)
+    }
+
+    /// Average number of bytes per write to the underlying connection.
+    pub fn bytes_per_write(&self) -> Option<f64> {
+        if self.transport_writes == 0 {
+            return None
+        }
+        Some(self.transport_bytes_written as f64 / self.transport_writes as f64)
+    }
+
+    /// The time frames spent in the connection at the given percentile
+    /// (0 to 100), or `None` if no frame has been sent yet.
+    ///
+    /// Measured from a stream handing a frame to the connection until the
+    /// underlying connection has been flushed after writing it, i.e. this
+    /// excludes network latency. Values are rounded up to the next greater
+    /// power of two microseconds.
+    pub fn queue_latency(&self, percentile: f64) -> Option<Duration> {
+        self.queue_latency.percentile(percentile)
+    }
+}
+
+/// Number of buckets of a `Histogram`. The last one has no upper bound.
+const BUCKETS: usize = 32;
+
+/// Counts durations in buckets of powers of two microseconds.
+///
+/// Bucket `i` holds durations below 2^i µs which do not fit into bucket
+/// `i - 1`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64
+}
+
+impl Histogram {
+    pub(crate) fn record(&mut self, d: Duration) {
+        let micros = d.as_secs()
+            .saturating_mul(1_000_000)
+            .saturating_add(u64::from(d.subsec_micros()));
+        let i = (64 - micros.leading_zeros()) as usize;
+        self.buckets[min(i, BUCKETS - 1)] += 1;
+        self.count += 1
+    }
+
+    /// The upper bound of the bucket containing the given percentile.
+    pub(crate) fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None
+        }
+        let rank = (percentile / 100.0 * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << i))
+            }
+        }
+        Some(Duration::from_micros(1 << (BUCKETS - 1)))
+    }
+}
+
+/// Wraps the underlying connection to count writes.
+#[derive(Debug)]
+pub(crate) struct Counted<T> {
+    io: T,
+    pub(crate) writes: u64,
+    pub(crate) bytes_written: u64
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(io: T) -> Self {
+        Counted { io, writes: 0, bytes_written: 0 }
+    }
+}
+
+impl<T: io::Read> io::Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Counted<T> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [u8]) -> bool {
+        self.io.prepare_uninitialized_buffer(buf)
+    }
+}
+
+impl<T: io::Write> io::Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.io.write(buf)?;
+        if n > 0 {
+            self.writes += 1;
+            self.bytes_written += n as u64
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for Counted<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_bucket_bounds() {
+        let mut histogram = Histogram::default();
+        assert_eq!(None, histogram.percentile(50.0));
+        for micros in 1 .. 100 {
+            histogram.record(Duration::from_micros(micros))
+        }
+        histogram.record(Duration::from_secs(1));
+        assert_eq!(Some(Duration::from_micros(2)), histogram.percentile(0.0));
+        assert_eq!(Some(Duration::from_micros(64)), histogram.percentile(50.0));
+        assert_eq!(Some(Duration::from_micros(128)), histogram.percentile(99.0));
+        assert_eq!(Some(Duration::from_micros(1 << 20)), histogram.percentile(100.0));
+        histogram.record(Duration::from_secs(u64::MAX));
+        assert_eq!(Some(Duration::from_micros(1 << 31)), histogram.percentile(100.0))
+    }
+}
@@ -48,7 +48,6 @@ impl fmt::Display for Id {
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum State {
     Open,
-    #[allow(dead_code)]
     SendClosed,
     RecvClosed,
     Closed
@@ -59,6 +58,10 @@ pub(crate) struct StreamEntry {
     state: State,
     pub(crate) window: u32,
     pub(crate) credit: u32,
+    pub(crate) consumed: u32,
+    /// Both sides have agreed to disable flow control, i.e. neither the
+    /// receive window nor the send credit are counted.
+    pub(crate) unlimited: bool,
     pub(crate) buffer: Arc<Mutex<BytesMut>>
 }
 
@@ -68,10 +71,16 @@ impl StreamEntry {
             state: State::Open,
             buffer: Arc::new(Mutex::new(BytesMut::new())),
             window,
-            credit
+            credit,
+            consumed: 0,
+            unlimited: false
         }
     }
 
+    pub(crate) fn state(&self) -> State {
+        self.state
+    }
+
     pub(crate) fn update_state(&mut self, next: State) {
         use self::State::*;
 
@@ -0,0 +1,95 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! Tests of how outbound frames share the connection.
+//!
+//! Streams have no priorities, so only equal shares are checked here. Tests
+//! for shares shifted by priorities belong with an implementation of them.
+
+extern crate env_logger;
+extern crate futures;
+extern crate tokio;
+extern crate yamux;
+
+use futures::{future, prelude::*, task};
+use std::collections::HashMap;
+use tokio::{io::{AsyncRead, AsyncWrite}, runtime::current_thread::Runtime};
+use yamux::{iter_frames, memory, Config, Connection, FrameType, Mode, StreamHandle};
+
+const STREAMS: usize = 10;
+const CHUNK: usize = 4 * 1024;
+/// Min. number of data bytes on the wire over which shares are measured.
+const MEASURE: usize = 1024 * 1024;
+/// Allowed deviation from an equal share, in percent.
+const TOLERANCE: f64 = 1.0;
+
+/// Write chunks for as long as the stream has credit, yielding after each one
+/// like a well-behaved application would.
+fn saturate(mut stream: StreamHandle<memory::Endpoint>) -> impl Future<Item=(), Error=()> {
+    let chunk = [0x5a; CHUNK];
+    future::poll_fn(move || {
+        match stream.poll_write(&chunk) {
+            Ok(Async::Ready(_)) => {
+                task::current().notify();
+                Ok(Async::NotReady)
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(())
+        }
+    })
+}
+
+/// Outbound frames are sent in the order they have been written, so streams
+/// written to at the same pace get equal shares of the connection.
+#[test]
+fn saturating_streams_get_equal_shares() {
+    let _ = env_logger::try_init();
+    let mut rt = Runtime::new().unwrap();
+    let (a, mut remote) = memory::pair_with_capacity(64 * 1024);
+    let client = Connection::new(a, Config::default(), Mode::Client);
+
+    for _ in 0 .. STREAMS {
+        rt.spawn(saturate(client.open_stream().unwrap().unwrap()));
+    }
+    rt.spawn(client.clone().for_each(|_| Ok(())).map_err(|e| panic!("connection error: {}", e)));
+
+    // Capture the raw bytes and decode them afterwards. Headers take up less
+    // than 1% of the capture, so it contains more than `MEASURE` data bytes.
+    let mut captured = Vec::new();
+    let capture = future::poll_fn(move || {
+        let mut buf = [0; 16 * 1024];
+        while captured.len() < MEASURE + MEASURE / 100 {
+            match remote.poll_read(&mut buf) {
+                Ok(Async::Ready(0)) => panic!("unexpected eof"),
+                Ok(Async::Ready(n)) => captured.extend_from_slice(&buf[.. n]),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(Async::Ready(std::mem::replace(&mut captured, Vec::new())))
+    });
+    let captured = rt.block_on(capture).unwrap();
+
+    let mut shares = HashMap::new();
+    let mut total = 0;
+    for frame in iter_frames(&captured).take_while(Result::is_ok).map(Result::unwrap) {
+        if frame.dyn_type() == FrameType::Data {
+            *shares.entry(frame.stream_id()).or_insert(0) += frame.body().len();
+            total += frame.body().len()
+        }
+    }
+    assert!(total >= MEASURE);
+    assert_eq!(STREAMS, shares.len());
+    for (id, n) in shares {
+        let share = n as f64 * 100.0 / total as f64;
+        let expected = 100.0 / STREAMS as f64;
+        assert!((share - expected).abs() <= TOLERANCE, "stream {}: {:.2}% of bytes", id, share)
+    }
+}
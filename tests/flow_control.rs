@@ -0,0 +1,177 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+extern crate env_logger;
+extern crate futures;
+extern crate tokio;
+extern crate yamux;
+
+use futures::{future::{self, Loop}, prelude::*, sync::oneshot};
+use tokio::{io::{self, AsyncRead}, runtime::current_thread::Runtime};
+use yamux::{memory, Config, Connection, Mode, StreamHandle, WindowUpdateMode};
+
+const WINDOW: usize = 256 * 1024;
+
+fn on_read_config() -> Config {
+    let mut cfg = Config::default();
+    cfg.set_window_update_mode(WindowUpdateMode::OnRead);
+    cfg
+}
+
+/// Read `n` bytes in chunks of `chunk` bytes.
+fn read_chunked<S>(stream: S, n: usize, chunk: usize) -> impl Future<Item=S, Error=std::io::Error>
+where
+    S: tokio::io::AsyncRead
+{
+    future::loop_fn((stream, 0), move |(stream, total)| {
+        io::read_exact(stream, vec![0; chunk]).map(move |(stream, _)| {
+            if total + chunk == n {
+                Loop::Break(stream)
+            } else {
+                Loop::Continue((stream, total + chunk))
+            }
+        })
+    })
+}
+
+/// Drive the connection until the stream's credit is non-zero.
+fn await_credit(conn: Connection<memory::Endpoint>, stream: &StreamHandle<memory::Endpoint>)
+    -> impl Future<Item=u32, Error=yamux::ConnectionError> + '_
+{
+    let mut conn = conn;
+    future::poll_fn(move || {
+        conn.poll()?;
+        match stream.credit() {
+            Some(0) | None => Ok(Async::NotReady),
+            Some(n) => Ok(Async::Ready(n))
+        }
+    })
+}
+
+#[test]
+fn window_is_credited_by_bytes_read() {
+    let _ = env_logger::try_init();
+    let mut rt = Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let server = Connection::new(b, on_read_config(), Mode::Server);
+
+    // Exhaust the client's credit.
+    let outbound = client.open_stream().unwrap().unwrap();
+    let outbound = rt.block_on(io::write_all(outbound, vec![1; WINDOW]).and_then(|(s, _)| io::flush(s))).unwrap();
+    assert_eq!(Some(0), outbound.credit());
+
+    // The server reads 3/4 of the window in small chunks, which credits the
+    // client with exactly the first half.
+    let (inbound, server) = rt.block_on(server.into_future()).map_err(|(e, _)| e).unwrap();
+    let inbound = rt.block_on(read_chunked(inbound.unwrap(), WINDOW / 4 * 3, 1024)).unwrap();
+
+    let credit = rt.block_on(await_credit(client.clone(), &outbound)).unwrap();
+    assert_eq!(WINDOW as u32 / 2, credit);
+    drop((inbound, server))
+}
+
+#[test]
+fn split_halves_share_window_accounting() {
+    let _ = env_logger::try_init();
+    let mut rt = Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let server = Connection::new(b, on_read_config(), Mode::Server);
+
+    let outbound = client.open_stream().unwrap().unwrap();
+    let outbound = rt.block_on(io::write_all(outbound, vec![1; WINDOW]).and_then(|(s, _)| io::flush(s))).unwrap();
+    let (inbound, server) = rt.block_on(server.into_future()).map_err(|(e, _)| e).unwrap();
+    let (reader, writer) = AsyncRead::split(inbound.unwrap());
+
+    // The writer half replies from a separate task while the reader half consumes.
+    let (tx, rx) = oneshot::channel();
+    rt.spawn(io::write_all(writer, b"pong").and_then(|(w, _)| io::flush(w)).then(|w| {
+        let _ = tx.send(w);
+        Ok(())
+    }));
+    let reader = rt.block_on(read_chunked(reader, WINDOW / 4 * 3, 1024)).unwrap();
+    let writer = rt.block_on(rx).unwrap().unwrap();
+
+    let credit = rt.block_on(await_credit(client.clone(), &outbound)).unwrap();
+    assert_eq!(WINDOW as u32 / 2, credit);
+    let (_, reply) = rt.block_on(io::read_exact(outbound, [0; 4])).unwrap();
+    assert_eq!(b"pong", &reply);
+    drop((reader, writer, server))
+}
+
+#[test]
+fn split_halves_can_be_dropped_in_any_order() {
+    let _ = env_logger::try_init();
+    for &drop_reader_first in &[true, false] {
+        let mut rt = Runtime::new().unwrap();
+        let (a, b) = memory::pair();
+        let client = Connection::new(a, Config::default(), Mode::Client);
+        let server = Connection::new(b, on_read_config(), Mode::Server);
+
+        let outbound = client.open_stream().unwrap().unwrap();
+        let outbound = rt.block_on(io::write_all(outbound, b"ping").and_then(|(s, _)| io::flush(s))).unwrap();
+        let (inbound, server) = rt.block_on(server.into_future()).map_err(|(e, _)| e).unwrap();
+        let (reader, writer) = AsyncRead::split(inbound.unwrap());
+
+        // The remaining half keeps working after the other one has been dropped.
+        let outbound =
+            if drop_reader_first {
+                drop(reader);
+                let writer = rt.block_on(io::write_all(writer, b"pong").and_then(|(w, _)| io::flush(w))).unwrap();
+                let (outbound, pong) = rt.block_on(io::read_exact(outbound, [0; 4])).unwrap();
+                assert_eq!(b"pong", &pong);
+                drop(writer);
+                outbound
+            } else {
+                drop(writer);
+                let (reader, ping) = rt.block_on(io::read_exact(reader, [0; 4])).unwrap();
+                assert_eq!(b"ping", &ping);
+                drop(reader);
+                outbound
+            };
+
+        // Once both halves are gone, the stream is reset.
+        rt.block_on(future::poll_fn(|| server.flush())).unwrap();
+        let (_, rest) = rt.block_on(io::read_to_end(outbound, Vec::new())).unwrap();
+        assert!(rest.is_empty());
+        drop(client)
+    }
+}
+
+#[test]
+fn effective_limits_bound_accepted_frames() {
+    let mut cfg = Config::default();
+    cfg.set_max_buffer_size(64 * 1024);
+    let limits = cfg.effective_limits();
+    assert_eq!(yamux::DEFAULT_CREDIT, limits.receive_window());
+    assert_eq!(64 * 1024, limits.max_frame_body());
+
+    cfg.set_max_buffer_size(4 * 1024 * 1024);
+    assert_eq!(WINDOW, cfg.effective_limits().max_frame_body())
+}
+
+#[test]
+fn max_windows_fit_frame_bodies_on_all_targets() {
+    let mut cfg = Config::default();
+    cfg.set_receive_window(u32::MAX).unwrap();
+    cfg.set_max_buffer_size(usize::MAX);
+    let limits = cfg.effective_limits();
+    assert_eq!(u32::MAX, limits.receive_window());
+    assert_eq!(u32::MAX as u64, limits.max_frame_body() as u64)
+}
+
+#[test]
+fn nested_transport_buffers_at_most_one_window() {
+    let limits = Config::for_nested_transport().effective_limits();
+    assert_eq!(WINDOW as u32, limits.receive_window());
+    assert_eq!(WINDOW, limits.max_frame_body());
+    assert_eq!(WINDOW, limits.max_buffer_size())
+}
@@ -0,0 +1,103 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+extern crate env_logger;
+extern crate futures;
+extern crate log;
+extern crate parking_lot;
+extern crate tokio;
+extern crate yamux;
+
+use futures::{future::{self, Loop}, prelude::*};
+use log::error;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::{io, runtime::current_thread::{self, Runtime}};
+use yamux::{memory, Config, Connection, Mode};
+
+const CONNECTIONS: usize = 300;
+const ROUNDS: usize = 10;
+const MSG_LEN: usize = 4096;
+
+fn message(conn: usize, round: usize) -> Vec<u8> {
+    let mut msg = format!("{}/{}:", conn, round).into_bytes();
+    msg.resize(MSG_LEN, (conn % 251) as u8);
+    msg
+}
+
+fn echo_server(conn: Connection<memory::Endpoint>) -> impl Future<Item=(), Error=()> {
+    conn.for_each(|stream| {
+        let echo = io::read_exact(stream, vec![0; MSG_LEN])
+            .and_then(|(stream, msg)| io::write_all(stream, msg))
+            .and_then(|(stream, _)| io::flush(stream))
+            .map(|_| ())
+            .map_err(|e| error!("S: stream error: {}", e));
+        current_thread::spawn(echo);
+        Ok(())
+    })
+    .map_err(|e| error!("S: connection error: {}", e))
+}
+
+fn client(id: usize, conn: Connection<memory::Endpoint>, log: Arc<Mutex<Vec<usize>>>)
+    -> impl Future<Item=(), Error=()>
+{
+    future::loop_fn(0, move |round| {
+        let stream = conn.open_stream().expect("open stream").expect("connection alive");
+        let expected = message(id, round);
+        let log = log.clone();
+        io::write_all(stream, expected.clone())
+            .and_then(|(stream, _)| io::flush(stream))
+            .and_then(|stream| io::read_exact(stream, vec![0; MSG_LEN]))
+            .map_err(|e| panic!("C: stream error: {}", e))
+            .map(move |(_stream, answer)| {
+                assert_eq!(expected, answer, "connection {} got someone else's data", id);
+                log.lock().push(id);
+                if round + 1 == ROUNDS {
+                    Loop::Break(())
+                } else {
+                    Loop::Continue(round + 1)
+                }
+            })
+    })
+}
+
+#[test]
+fn many_connections_on_one_thread() {
+    let _ = env_logger::try_init();
+    let mut rt = Runtime::new().unwrap();
+    let log = Arc::new(Mutex::new(Vec::with_capacity(CONNECTIONS * ROUNDS)));
+
+    let clients = (0 .. CONNECTIONS).map(|id| {
+        let (a, b) = memory::pair();
+        rt.spawn(echo_server(Connection::new(b, Config::default(), Mode::Server)));
+        client(id, Connection::new(a, Config::default(), Mode::Client), log.clone())
+    })
+    .collect::<Vec<_>>();
+
+    rt.block_on(future::join_all(clients)).unwrap();
+
+    let log = log.lock();
+    assert_eq!(CONNECTIONS * ROUNDS, log.len());
+
+    // No connection may finish all its rounds before every other connection
+    // has made progress, i.e. the first `CONNECTIONS` completed rounds must
+    // not be dominated by a few connections.
+    let mut rounds = vec![0; CONNECTIONS];
+    for &id in log.iter().take(CONNECTIONS) {
+        rounds[id] += 1
+    }
+    assert!(rounds.iter().all(|&n| n < ROUNDS), "unfair scheduling: {:?}", rounds);
+
+    let mut rounds = vec![0; CONNECTIONS];
+    for &id in log.iter() {
+        rounds[id] += 1
+    }
+    assert!(rounds.iter().all(|&n| n == ROUNDS), "incomplete connections: {:?}", rounds)
+}
@@ -8,6 +8,7 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
+extern crate bytes;
 extern crate log;
 extern crate env_logger;
 extern crate futures;
@@ -15,12 +16,13 @@ extern crate tokio;
 extern crate tokio_codec;
 extern crate yamux;
 
-use futures::{future::{self, Either, Loop}, prelude::*, stream};
+use bytes::Bytes;
+use futures::{future::{self, Either, Loop}, prelude::*, stream, sync::oneshot};
 use log::{debug, error, warn};
-use std::io;
-use tokio::{net::{TcpListener, TcpStream}, runtime::Runtime};
+use std::{io, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::{TcpListener, TcpStream}, runtime::{current_thread, Runtime}};
 use tokio_codec::{BytesCodec, Framed};
-use yamux::{ConnectionError, Config, Connection, Mode};
+use yamux::{memory, ConfigProvider, ConnectionError, Config, Connection, EndReason, Inbound, Matcher, Mode};
 
 fn server_conn(addr: &str, cfg: Config) -> impl Future<Item=Connection<TcpStream>, Error=()> {
     TcpListener::bind(&addr.parse().unwrap())
@@ -109,3 +111,362 @@ fn connect_two_endpoints() {
     rt.spawn(echo_stream_ids);
     rt.block_on(client).unwrap();
 }
+
+#[test]
+fn serve_spawns_handler_per_stream() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let handled = Arc::new(AtomicUsize::new(0));
+
+    let server = {
+        let handled = handled.clone();
+        Connection::new(b, Config::default(), Mode::Server)
+            .serve(current_thread::TaskExecutor::current(), move |stream| {
+                handled.fetch_add(1, Ordering::SeqCst);
+                tokio::io::read_exact(stream, [0; 5])
+                    .and_then(|(stream, msg)| tokio::io::write_all(stream, msg))
+                    .and_then(|(stream, _)| tokio::io::flush(stream))
+                    .map(|_| ())
+                    .map_err(|e| error!("S: stream error: {}", e))
+            })
+    };
+    let (tx, rx) = oneshot::channel();
+    rt.spawn(server.then(move |result| {
+        let _ = tx.send(result.is_ok());
+        Ok(())
+    }));
+
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let requests = (0 .. 3).map(|_| {
+        let stream = client.open_stream().unwrap().unwrap();
+        tokio::io::write_all(stream, *b"hello")
+            .and_then(|(stream, _)| tokio::io::flush(stream))
+            .and_then(|stream| tokio::io::read_exact(stream, [0; 5]))
+            .map(|(_stream, answer)| assert_eq!(b"hello", &answer))
+    })
+    .collect::<Vec<_>>();
+
+    // Closing our end of the transport makes the server connection end.
+    let c = client.clone();
+    let served = rt.block_on(future::join_all(requests)
+        .and_then(move |_| future::poll_fn(move || c.close()))
+        .and_then(|()| rx.map_err(|_| io::Error::new(io::ErrorKind::Other, "server dropped")))
+        .map(move |served| {
+            drop(client);
+            served
+        }));
+
+    assert!(served.unwrap());
+    assert_eq!(3, handled.load(Ordering::SeqCst))
+}
+
+#[test]
+fn request_resolves_to_response() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+
+    let server = Connection::new(b, Config::default(), Mode::Server)
+        .serve_requests(current_thread::TaskExecutor::current(), |request: Bytes| {
+            Ok(Bytes::from(request.to_ascii_uppercase()))
+        });
+    rt.spawn(server.map_err(|e| error!("S: connection error: {}", e)));
+
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let large = Bytes::from(vec![b'x'; 512 * 1024]); // exceeds the initial credit
+    let requests = vec![client.request(Bytes::from("hello")), client.request(large.clone())];
+    let responses = rt.block_on(future::join_all(requests)).unwrap();
+    assert_eq!(Bytes::from("HELLO"), responses[0]);
+    assert_eq!(large.to_ascii_uppercase(), &responses[1][..]);
+
+    // Both streams have been closed without resets.
+    assert_eq!(0, client.stats().resets_sent());
+    assert!(client.debug_dump().streams().is_empty())
+}
+
+#[test]
+fn excessive_resets_make_server_go_away() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+
+    let mut cfg = Config::default();
+    cfg.set_max_resets_per_sec(Some(3));
+    let server = Connection::new(b, cfg, Mode::Server)
+        .for_each(|stream| {
+            drop(stream); // reject every inbound stream
+            Ok(())
+        })
+        .map_err(|e| error!("S: connection error: {}", e));
+    rt.spawn(server);
+
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let _streams = (0 .. 10)
+        .map(|_| client.open_stream().unwrap().unwrap())
+        .collect::<Vec<_>>();
+
+    // The client connection only ends once the server has sent its GoAway.
+    rt.block_on(client.clone().for_each(|_| Ok(()))).unwrap();
+    assert!(client.open_stream().unwrap().is_none())
+}
+
+#[test]
+fn builder_resolves_per_peer_config() {
+    struct Reputation;
+
+    impl ConfigProvider for Reputation {
+        type PeerId = &'static str;
+
+        fn config(&self, peer: &Self::PeerId, base: &Config) -> Config {
+            let mut cfg = base.clone();
+            if *peer == "untrusted" {
+                cfg.set_max_num_streams(1)
+            }
+            cfg
+        }
+    }
+
+    let (a, _b) = memory::pair();
+    let untrusted = Connection::builder(a, Mode::Server).peer(&Reputation, &"untrusted").build();
+    let _s = untrusted.open_stream().unwrap().unwrap();
+    match untrusted.open_stream() {
+        Err(ConnectionError::TooManyStreams) => {}
+        other => panic!("unexpected result: {:?}", other.map(|s| s.is_some()))
+    }
+
+    let (a, _b) = memory::pair();
+    let trusted = Connection::builder(a, Mode::Server).peer(&Reputation, &"trusted").build();
+    let _s1 = trusted.open_stream().unwrap().unwrap();
+    let _s2 = trusted.open_stream().unwrap().unwrap();
+}
+
+#[test]
+fn write_flush_delivers_without_further_polling() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let server = Connection::new(b, Config::default(), Mode::Server);
+
+    let mut stream = client.open_stream().unwrap().unwrap();
+    let n = rt.block_on(future::poll_fn(|| stream.write_flush(b"ping"))).unwrap();
+    assert_eq!(4, n);
+
+    // Neither the client connection nor its stream is used again, so the
+    // server only gets the message if `write_flush` did flush it.
+    let (inbound, _server) = rt.block_on(server.into_future()).map_err(|(e, _)| e).unwrap();
+    let (_, msg) = rt.block_on(tokio::io::read_exact(inbound.unwrap(), [0; 4])).unwrap();
+    assert_eq!(b"ping", &msg)
+}
+
+#[test]
+fn accept_inbound_streams_in_batches() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let server = Connection::new(b, Config::default(), Mode::Server);
+
+    let _outbound = rt.block_on(future::lazy(|| {
+        let streams = (0 .. 5u8)
+            .map(|i| {
+                let mut stream = client.open_stream().unwrap().unwrap();
+                io::Write::write_all(&mut stream, &[i]).unwrap();
+                stream
+            })
+            .collect::<Vec<_>>();
+        future::poll_fn(|| client.flush()).map(|()| streams)
+    }))
+    .unwrap();
+
+    let first = rt.block_on(future::poll_fn(|| server.poll_incoming_batch(3))).unwrap().unwrap();
+    let second = rt.block_on(future::poll_fn(|| server.poll_incoming_batch(3))).unwrap().unwrap();
+    assert_eq!(3, first.len());
+    assert_eq!(2, second.len());
+
+    for (i, stream) in first.into_iter().chain(second).enumerate() {
+        let (_, body) = rt.block_on(tokio::io::read_exact(stream, [0; 1])).unwrap();
+        assert_eq!(i as u8, body[0])
+    }
+}
+
+#[test]
+fn debug_dump_redacts_bodies_by_default() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, _b) = memory::pair();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+
+    let _stream = rt.block_on(future::lazy(|| {
+        let mut stream = client.open_stream().unwrap().unwrap();
+        io::Write::write_all(&mut stream, b"secret").map(|()| stream)
+    }))
+    .unwrap();
+
+    let dump = client.debug_dump();
+    assert_eq!(1, dump.streams().len());
+    assert_eq!(1, dump.pending().len());
+    assert_eq!(None, dump.pending()[0].body());
+    assert!(dump.to_string().contains("\"body\":null"));
+
+    let dump = client.debug_dump_with_bodies();
+    assert_eq!(Some(&b"secret"[..]), dump.pending()[0].body());
+    assert!(dump.to_string().contains("\"body\":\"736563726574\""))
+}
+
+#[test]
+fn expected_stream_bypasses_accept_loop() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let server = Connection::new(b, Config::default(), Mode::Server);
+    let reply = server.expect_stream(Matcher::FirstBytes(Box::new(|data| data.starts_with(b"reply"))));
+
+    let _outbound = rt.block_on(future::lazy(|| {
+        let streams = [&b"request"[..], &b"reply"[..]].iter()
+            .map(|data| {
+                let mut stream = client.open_stream().unwrap().unwrap();
+                io::Write::write_all(&mut stream, data).unwrap();
+                stream
+            })
+            .collect::<Vec<_>>();
+        future::poll_fn(|| client.flush()).map(|()| streams)
+    }))
+    .unwrap();
+
+    let reply = rt.block_on(reply).unwrap();
+    let (_, body) = rt.block_on(tokio::io::read_exact(reply, [0; 5])).unwrap();
+    assert_eq!(b"reply", &body);
+
+    let (request, _server) = rt.block_on(server.into_future()).map_err(|(e, _)| e).unwrap();
+    let (_, body) = rt.block_on(tokio::io::read_exact(request.unwrap(), [0; 7])).unwrap();
+    assert_eq!(b"request", &body)
+}
+
+#[test]
+fn shutdown_signal_closes_connection_gracefully() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let (tx, rx) = oneshot::channel::<()>();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let server = Connection::builder(b, Mode::Server).shutdown_signal(rx).build();
+
+    tx.send(()).unwrap();
+    rt.block_on(server.for_each(|_| Ok(()))).unwrap();
+
+    // The client learns about the shutdown from the GoAway frame.
+    let (stream, _) = rt.block_on(client.into_future()).map_err(|(e, _)| e).unwrap();
+    assert!(stream.is_none())
+}
+
+#[test]
+fn ping_measures_round_trip_time() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (a, b) = memory::pair();
+    let client = Connection::new(a, Config::default(), Mode::Client);
+    let server = Connection::new(b, Config::default(), Mode::Server);
+    rt.spawn(server.for_each(|_| Ok(())).map_err(|e| error!("server error: {}", e)));
+
+    client.ping().unwrap();
+    let c = client.clone();
+    rt.block_on(future::poll_fn(move || {
+        c.flush()?;
+        let mut c = c.clone();
+        c.poll()?;
+        if c.stats().rtt().is_none() {
+            return Ok(Async::NotReady)
+        }
+        Ok::<_, ConnectionError>(Async::Ready(()))
+    }))
+    .unwrap();
+
+    let stats = client.stats();
+    assert_eq!(1, stats.pings_sent());
+    assert_eq!(0, stats.unexpected_pongs())
+}
+
+#[test]
+fn incoming_ends_with_reason() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+
+    let (client, server) = Connection::pair(Config::default());
+    let c = client.clone();
+    rt.block_on(future::poll_fn(|| c.close())).unwrap();
+    match rt.block_on(server.incoming().collect()).unwrap().as_slice() {
+        [Inbound::End(EndReason::GoAway(0))] => {}
+        _ => panic!("server did not end with the client's go away")
+    }
+    let mut incoming = client.incoming();
+    match rt.block_on(incoming.by_ref().into_future()).map_err(|(e, _)| e).unwrap().0 {
+        Some(Inbound::End(EndReason::Closed)) => {}
+        _ => panic!("client did not end with local close")
+    }
+    assert!(incoming.is_terminated());
+    assert!(rt.block_on(incoming.into_future()).map_err(|(e, _)| e).unwrap().0.is_none());
+
+    let (client, server) = Connection::pair(Config::default());
+    drop(client);
+    match rt.block_on(server.incoming().collect()).unwrap().as_slice() {
+        [Inbound::End(EndReason::Eof)] => {}
+        _ => panic!("server did not end with eof")
+    }
+}
+
+/// Send a message over a new stream from `client` to `server`.
+fn say_hello<A, B>(rt: &mut current_thread::Runtime, client: Connection<A>, server: Connection<B>)
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite
+{
+    let mut stream = client.open_stream().unwrap().unwrap();
+    assert_eq!(5, rt.block_on(future::poll_fn(|| stream.write_flush(b"hello"))).unwrap());
+    let (inbound, _server) = rt.block_on(server.into_future()).map_err(|(e, _)| e).unwrap();
+    let (_, msg) = rt.block_on(tokio::io::read_exact(inbound.unwrap(), [0; 5])).unwrap();
+    assert_eq!(b"hello", &msg)
+}
+
+#[test]
+fn pair_connects_client_and_server() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let (client, server) = Connection::pair(Config::default());
+
+    say_hello(&mut rt, client, server)
+}
+
+#[cfg(feature = "tokio-tcp")]
+#[test]
+fn connect_over_tcp() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = rt.block_on(Connection::over_tcp(&addr, Config::default())).unwrap();
+    let (socket, _) = rt.block_on(listener.incoming().into_future()).map_err(|(e, _)| e).unwrap();
+    let server = Connection::new(socket.unwrap(), Config::default(), Mode::Server);
+
+    say_hello(&mut rt, client, server)
+}
+
+#[cfg(all(unix, feature = "tokio-uds"))]
+#[test]
+fn connect_over_unix_socket() {
+    let _ = env_logger::try_init();
+    let mut rt = current_thread::Runtime::new().unwrap();
+    let path = std::env::temp_dir().join(format!("yamux-smoke-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+    let client = rt.block_on(Connection::over_unix_socket(&path, Config::default())).unwrap();
+    let (socket, _) = rt.block_on(listener.incoming().into_future()).map_err(|(e, _)| e).unwrap();
+    let server = Connection::new(socket.unwrap(), Config::default(), Mode::Server);
+    let _ = std::fs::remove_file(&path);
+
+    say_hello(&mut rt, client, server)
+}